@@ -0,0 +1,112 @@
+use geo::{Coord, LineString, MultiPolygon, Polygon};
+
+use super::{LonLat, LonLatRect, WorldSpace};
+
+/// What area the user asked for, either by its name or by the id of its boundary relation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AreaRef {
+    Name(String),
+    Relation(i64),
+}
+
+impl AreaRef {
+    /// Reads what was typed in, `123`, `r123` and `relation/123` are all taken as relation ids.
+    pub fn parse(input: &str) -> Option<Self> {
+        let input = input.trim();
+        if input.is_empty() {
+            return None;
+        }
+        let id = input.trim_start_matches("relation/").trim_start_matches('r');
+        match id.parse::<i64>() {
+            Ok(id) if id > 0 => Some(AreaRef::Relation(id)),
+            _ => Some(AreaRef::Name(input.to_string())),
+        }
+    }
+
+    /// The overpass statement which puts the area into `.a`.
+    pub fn area_statement(&self) -> String {
+        match self {
+            // Areas made from relations have the relation id plus this
+            AreaRef::Relation(id) => format!("area({})->.a;", 3600000000 + id),
+            AreaRef::Name(name) => format!(r#"area["name"="{}"]["boundary"="administrative"]->.a;"#, name.replace('\\', "\\\\").replace('"', "\\\"")),
+        }
+    }
+}
+
+/// The boundary of an area, each ring is closed.
+#[derive(Clone, Debug, Default)]
+pub struct AreaBoundary {
+    pub name: String,
+    pub rings: Vec<Vec<LonLat>>,
+}
+
+impl AreaBoundary {
+    /// The bounding box of every ring.
+    pub fn bounds(&self) -> Option<LonLatRect> {
+        let mut points = self.rings.iter().flatten();
+        let first = *points.next()?;
+        Some(points.fold(LonLatRect::from_corners(first, first), |rect, p| {
+            LonLatRect::from_corners(
+                LonLat::new(rect.min.lon.min(p.lon), rect.min.lat.min(p.lat)),
+                LonLat::new(rect.max.lon.max(p.lon), rect.max.lat.max(p.lat)),
+            )
+        }))
+    }
+
+    /// The rings in world space, ready for drawing.
+    pub fn rings_in_world_space(&self, world_space: &WorldSpace) -> Vec<Vec<bevy::math::Vec2>> {
+        self.rings
+            .iter()
+            .map(|ring| ring.iter().map(|p| world_space.to_world(*p).into()).collect())
+            .collect()
+    }
+
+    /// The rings as polygons, for checking what is inside of the area.
+    pub fn polygons(&self) -> MultiPolygon {
+        MultiPolygon(
+            self.rings
+                .iter()
+                .filter(|ring| ring.len() > 3)
+                .map(|ring| Polygon::new(LineString(ring.iter().map(|p| Coord::from(*p)).collect()), vec![]))
+                .collect(),
+        )
+    }
+}
+
+/// Joins the ways of a boundary relation end to end into closed rings.
+pub fn assemble_rings(mut segments: Vec<Vec<LonLat>>) -> Vec<Vec<LonLat>> {
+    segments.retain(|s| s.len() > 1);
+    let mut rings = Vec::new();
+
+    while let Some(mut ring) = segments.pop() {
+        while ring.first() != ring.last() {
+            let end = *ring.last().unwrap();
+            let Some(i) = segments.iter().position(|s| s.first() == Some(&end) || s.last() == Some(&end)) else {
+                break;
+            };
+            let mut next = segments.swap_remove(i);
+            if next.first() != Some(&end) {
+                next.reverse();
+            }
+            ring.extend(next.into_iter().skip(1));
+        }
+        // Anything that couldn't be closed is still worth drawing, so close it off
+        if ring.first() != ring.last() {
+            ring.push(ring[0]);
+        }
+        rings.push(ring);
+    }
+
+    rings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_escaped_for_overpass() {
+        let area = AreaRef::Name(r#"Foo\" Bar"#.to_string());
+        assert_eq!(area.area_statement(), r#"area["name"="Foo\\\" Bar"]["boundary"="administrative"]->.a;"#);
+    }
+}
@@ -0,0 +1,63 @@
+use geo::{BoundingRect, LineString, SimplifyVw};
+
+use super::{is_line, metres_per_tile_pixel, MapFeature};
+
+/// The zoom levels each level of detail is simplified for, a level is used from two zoom levels below its own up to it.
+/// Past the last one features are drawn with every vertex they have.
+const DETAIL_ZOOMS: [f64; 4] = [12.0, 14.0, 16.0, 18.0];
+/// The level of detail where nothing is simplified.
+pub const FULL_DETAIL: usize = DETAIL_ZOOMS.len();
+/// How many levels of detail a feature can have cached, not counting full detail which is the geometry itself.
+pub const DETAIL_LEVELS: usize = DETAIL_ZOOMS.len();
+/// Roughly how many metres a degree of latitude is.
+const METRES_PER_DEGREE: f64 = 111_320.0;
+
+/// The level of detail features are drawn at for a zoom level.
+pub fn detail_level(zoom: f64) -> usize {
+    DETAIL_ZOOMS.iter().position(|level_zoom| zoom < *level_zoom).unwrap_or(FULL_DETAIL)
+}
+
+/// The zoom level in the middle of where a level of detail is used, which is what styles are worked out for.
+pub fn detail_zoom(level: usize) -> f64 {
+    match DETAIL_ZOOMS.get(level) {
+        Some(zoom) => zoom - 1.0,
+        None => DETAIL_ZOOMS[DETAIL_ZOOMS.len() - 1] + 1.0,
+    }
+}
+
+/// The outline of a feature as it is drawn at a level of detail, none if it is too small to see at all.
+/// Each level is only simplified once, after that it comes out of the feature's cache.
+pub fn simplified(feature: &MapFeature, level: usize) -> Option<&geo::Polygon> {
+    if level >= FULL_DETAIL {
+        return Some(&feature.geometry);
+    }
+    feature.detail[level].get_or_init(|| simplify(&feature.geometry, DETAIL_ZOOMS[level], is_line(feature))).as_ref()
+}
+
+/// Drops vertices which would move the outline by less than half a pixel at `zoom`, which is the most zoomed in the
+/// level is used at, so it never looks any different. Anything smaller than a pixel there is dropped altogether.
+/// A `line` is simplified without the edge that closes the polygon, which isn't drawn, so both of its ends stay where they are.
+fn simplify(geometry: &geo::Polygon, zoom: f64, line: bool) -> Option<geo::Polygon> {
+    let bbox = geometry.bounding_rect()?;
+    let lat_cos = bbox.center().y.to_radians().cos();
+    let pixel = metres_per_tile_pixel(zoom, bbox.center().y);
+
+    // Work in degrees of latitude, which a degree of longitude is a bit shorter than
+    let pixel_degrees = pixel / METRES_PER_DEGREE;
+    if bbox.width() * lat_cos < pixel_degrees && bbox.height() < pixel_degrees {
+        return None;
+    }
+    let epsilon = (pixel_degrees / 2.0).powi(2) / lat_cos;
+    if line {
+        let mut points = geometry.exterior().0.clone();
+        points.pop();
+        // Making it a polygon again closes it back up, the same as the original
+        return Some(geo::Polygon::new(LineString(points).simplify_vw(&epsilon), Vec::new()));
+    }
+    let simplified = geometry.simplify_vw(&epsilon);
+    // A ring needs at least three corners and to end where it started, otherwise it is left alone
+    if simplified.exterior().0.len() < 4 {
+        return Some(geometry.clone());
+    }
+    Some(simplified)
+}
@@ -0,0 +1,258 @@
+use std::io::BufRead;
+
+use quick_xml::{events::{BytesStart, Event}, Reader};
+
+use super::{FeatureId, LonLat, MapFeature, OsmType};
+
+/// A single change to a way taken from an overpass augmented diff.
+#[derive(Clone, Debug)]
+pub enum OsmChange {
+    Create(MapFeature),
+    Modify { new: MapFeature },
+    Delete { id: FeatureId, old: Option<MapFeature> },
+}
+
+/// An augmented diff, the timestamp is the state of the database the diff goes up to.
+#[derive(Clone, Debug, Default)]
+pub struct OsmDiff {
+    pub timestamp: Option<String>,
+    pub changes: Vec<OsmChange>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ActionKind {
+    Create,
+    Modify,
+    Delete,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Old,
+    New,
+}
+
+/// A way which is still being read in.
+struct PartialWay {
+    id: FeatureId,
+    visible: bool,
+    tags: serde_json::Map<String, serde_json::Value>,
+    geometry: Vec<LonLat>,
+}
+
+impl PartialWay {
+    fn into_feature(self) -> Option<MapFeature> {
+        if self.geometry.is_empty() || !self.visible {
+            return None;
+        }
+        Some(MapFeature::from_lon_lats(self.id, serde_json::Value::Object(self.tags), self.geometry))
+    }
+}
+
+/// Parses an overpass augmented diff (`[adiff:...]` with `out geom`), only ways are kept as that is all the map draws.
+pub fn get_diff_from_reader_osm<R: BufRead>(reader: R) -> Result<OsmDiff, Box<dyn std::error::Error>> {
+    let mut reader = Reader::from_reader(reader);
+    let mut buf = Vec::new();
+    let mut diff = OsmDiff::default();
+
+    let mut action = None;
+    let mut side = None;
+    let mut old = None;
+    let mut new = None;
+    let mut way: Option<PartialWay> = None;
+
+    loop {
+        let event = reader.read_event_into(&mut buf)?;
+        let empty = matches!(event, Event::Empty(_));
+        match event {
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"meta" => diff.timestamp = attribute(&e, "osm_base")?,
+                b"action" => {
+                    action = match attribute(&e, "type")?.as_deref() {
+                        Some("create") => Some(ActionKind::Create),
+                        Some("modify") => Some(ActionKind::Modify),
+                        Some("delete") => Some(ActionKind::Delete),
+                        _ => None,
+                    };
+                    old = None;
+                    new = None;
+                }
+                b"old" => side = Some(Side::Old),
+                b"new" => side = Some(Side::New),
+                b"way" => {
+                    let partial = PartialWay {
+                        id: FeatureId(OsmType::Way, attribute(&e, "id")?.and_then(|id| id.parse().ok()).unwrap_or_default()),
+                        visible: attribute(&e, "visible")?.as_deref() != Some("false"),
+                        tags: serde_json::Map::new(),
+                        geometry: Vec::new(),
+                    };
+                    if empty {
+                        finish_way(partial, side, &mut old, &mut new);
+                    } else {
+                        way = Some(partial);
+                    }
+                }
+                b"nd" => {
+                    if let Some(way) = way.as_mut() {
+                        let lat = attribute(&e, "lat")?.and_then(|v| v.parse::<f64>().ok());
+                        let lon = attribute(&e, "lon")?.and_then(|v| v.parse::<f64>().ok());
+                        if let (Some(lat), Some(lon)) = (lat, lon) {
+                            way.geometry.push(LonLat::new(lon, lat));
+                        }
+                    }
+                }
+                b"tag" => {
+                    if let (Some(way), Some(k), Some(v)) = (way.as_mut(), attribute(&e, "k")?, attribute(&e, "v")?) {
+                        way.tags.insert(k, serde_json::Value::String(v));
+                    }
+                }
+                _ => {}
+            },
+            Event::End(e) => match e.local_name().as_ref() {
+                b"way" => {
+                    if let Some(partial) = way.take() {
+                        finish_way(partial, side, &mut old, &mut new);
+                    }
+                }
+                b"old" | b"new" => side = None,
+                b"action" => {
+                    if let Some(change) = finish_action(action.take(), old.take(), new.take()) {
+                        diff.changes.push(change);
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(diff)
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    match e.try_get_attribute(name)? {
+        Some(attr) => Ok(Some(attr.unescape_value()?.into_owned())),
+        None => Ok(None),
+    }
+}
+
+/// A create has no old or new wrapper, so anything outside of one counts as the new version.
+fn finish_way(way: PartialWay, side: Option<Side>, old: &mut Option<PartialWay>, new: &mut Option<PartialWay>) {
+    match side {
+        Some(Side::Old) => *old = Some(way),
+        Some(Side::New) | None => *new = Some(way),
+    }
+}
+
+fn finish_action(action: Option<ActionKind>, old: Option<PartialWay>, new: Option<PartialWay>) -> Option<OsmChange> {
+    match action? {
+        ActionKind::Create => Some(OsmChange::Create(new?.into_feature()?)),
+        ActionKind::Modify => {
+            let new = new?;
+            // A way that has been hidden is as good as deleted
+            if !new.visible {
+                return Some(OsmChange::Delete { id: new.id, old: old.and_then(PartialWay::into_feature) });
+            }
+            Some(OsmChange::Modify { new: new.into_feature()? })
+        }
+        ActionKind::Delete => {
+            let id = old.as_ref().or(new.as_ref())?.id;
+            Some(OsmChange::Delete { id, old: old.and_then(PartialWay::into_feature) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6" generator="Overpass API">
+<meta osm_base="2025-01-10T12:34:56Z"/>
+<action type="create">
+  <way id="1" version="1">
+    <nd ref="10" lat="52.2" lon="0.1"/>
+    <nd ref="11" lat="52.3" lon="0.2"/>
+    <tag k="highway" v="residential"/>
+  </way>
+</action>
+<action type="modify">
+  <old>
+    <way id="2" version="1">
+      <nd ref="12" lat="52.2" lon="0.1"/>
+      <nd ref="13" lat="52.3" lon="0.2"/>
+      <tag k="building" v="yes"/>
+    </way>
+  </old>
+  <new>
+    <way id="2" version="2">
+      <nd ref="12" lat="52.2" lon="0.1"/>
+      <nd ref="13" lat="52.4" lon="0.3"/>
+      <tag k="building" v="house"/>
+    </way>
+  </new>
+</action>
+<action type="modify">
+  <old>
+    <way id="3" version="1">
+      <nd ref="14" lat="52.2" lon="0.1"/>
+      <nd ref="15" lat="52.3" lon="0.2"/>
+    </way>
+  </old>
+  <new>
+    <way id="3" version="2" visible="false"/>
+  </new>
+</action>
+<action type="delete">
+  <old>
+    <way id="4" version="3">
+      <nd ref="16" lat="52.2" lon="0.1"/>
+      <nd ref="17" lat="52.3" lon="0.2"/>
+      <tag k="railway" v="rail"/>
+    </way>
+  </old>
+  <new>
+    <way id="4" version="4" visible="false"/>
+  </new>
+</action>
+<action type="create">
+  <node id="5" version="1" lat="52.2" lon="0.1"/>
+</action>
+</osm>"#;
+
+    #[test]
+    fn reads_each_kind_of_change() {
+        let diff = get_diff_from_reader_osm(DIFF.as_bytes()).unwrap();
+        assert_eq!(diff.timestamp.as_deref(), Some("2025-01-10T12:34:56Z"));
+        // The node is skipped, only ways are kept
+        assert_eq!(diff.changes.len(), 4);
+
+        let OsmChange::Create(created) = &diff.changes[0] else { panic!("expected a create, got {:?}", diff.changes[0]) };
+        assert_eq!(created.id, FeatureId(OsmType::Way, 1));
+        assert_eq!(created.properties["highway"], "residential");
+        assert_eq!(created.lon_lats().next(), Some(LonLat::new(0.1, 52.2)));
+
+        let OsmChange::Modify { new } = &diff.changes[1] else { panic!("expected a modify, got {:?}", diff.changes[1]) };
+        assert_eq!(new.id, FeatureId(OsmType::Way, 2));
+        assert_eq!(new.properties["building"], "house");
+        assert!(new.lon_lats().any(|point| point == LonLat::new(0.3, 52.4)));
+
+        // Hidden by a modify, so it goes the same as a delete
+        let OsmChange::Delete { id, old } = &diff.changes[2] else { panic!("expected a delete, got {:?}", diff.changes[2]) };
+        assert_eq!(*id, FeatureId(OsmType::Way, 3));
+        assert!(old.is_some());
+
+        let OsmChange::Delete { id, old } = &diff.changes[3] else { panic!("expected a delete, got {:?}", diff.changes[3]) };
+        assert_eq!(*id, FeatureId(OsmType::Way, 4));
+        assert_eq!(old.as_ref().unwrap().properties["railway"], "rail");
+    }
+
+    #[test]
+    fn empty_diff_has_no_changes() {
+        let diff = get_diff_from_reader_osm(r#"<osm><meta osm_base="2025-01-10T12:34:56Z"/></osm>"#.as_bytes()).unwrap();
+        assert!(diff.changes.is_empty());
+        assert_eq!(diff.timestamp.as_deref(), Some("2025-01-10T12:34:56Z"));
+    }
+}
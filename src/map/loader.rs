@@ -1,31 +1,151 @@
-use std::{fs::File, io::BufReader};
+use std::{fs::File, io::{BufReader, Read}, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-use bevy::prelude::*;
 use geojson::GeoJson;
-use serde::{Deserialize, Serialize};
+use serde::{de::{DeserializeSeed, Error as _, IgnoredAny, MapAccess, SeqAccess, Visitor}, Deserialize, Deserializer, Serialize};
 
-use super::MapFeature;
+use super::{FeatureId, LonLat, MapFeature, OsmType};
 
-/// Parses OSM data from a string and returns a vector of map features.
-pub fn get_data_from_string_osm(data: &str) -> Result<Vec<MapFeature>, Box<dyn std::error::Error>> {
-    let response: OverpassResponse = serde_json::from_str(data)?;
+/// How many features are gathered before a chunk is handed on while streaming a response.
+pub const FEATURE_CHUNK_SIZE: usize = 512;
 
-    let mut features = Vec::new();
+/// The header of an overpass response, this says where the data came from and how fresh it is.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct OverpassMeta {
+    pub generator: Option<String>,
+    pub timestamp_osm_base: Option<String>,
+    pub copyright: Option<String>,
+}
+
+/// Parses OSM data straight from a reader, walking the `elements` array one element at a time.
+/// Every `chunk_size` features are handed to `on_chunk` so they can be shown before the rest of the response has arrived,
+/// returning false from `on_chunk` stops the parse early.
+pub fn stream_data_from_reader_osm<R: Read>(
+    reader: R,
+    chunk_size: usize,
+    mut on_chunk: impl FnMut(Vec<MapFeature>) -> bool,
+) -> Result<OverpassMeta, Box<dyn std::error::Error>> {
+    // serde_json reads byte by byte, so it needs to be buffered
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+    let meta = OverpassStream { chunk_size: chunk_size.max(1), on_chunk: &mut on_chunk }.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+
+    Ok(meta)
+}
+
+/// Turns an overpass timestamp such as `2025-01-10T12:34:56Z` into a system time.
+pub fn parse_osm_timestamp(timestamp: &str) -> Option<SystemTime> {
+    let (date, time) = timestamp.trim().trim_end_matches('Z').split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.splitn(3, ':').map(|p| p.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the unix epoch, see http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second;
+    u64::try_from(seconds).ok().map(|s| UNIX_EPOCH + Duration::from_secs(s))
+}
+
+fn section_to_feature(way: Section) -> Option<MapFeature> {
+    // Ensure geometry exists
+    let geometry = way.geometry;
+    if geometry.is_empty() {
+        return None;
+    }
+    Some(MapFeature::from_lon_lats(
+        FeatureId(OsmType::parse(&way.type_field)?, way.id),
+        way.tags.unwrap_or_default(),
+        geometry.into_iter().map(|p| LonLat::new(p.lon, p.lat)),
+    ))
+}
+
+/// Walks the top level of an overpass response, only `elements` is looked at and everything else is skipped.
+struct OverpassStream<'a, F> {
+    chunk_size: usize,
+    on_chunk: &'a mut F,
+}
+
+impl<'de, F: FnMut(Vec<MapFeature>) -> bool> DeserializeSeed<'de> for OverpassStream<'_, F> {
+    type Value = OverpassMeta;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, F: FnMut(Vec<MapFeature>) -> bool> Visitor<'de> for OverpassStream<'_, F> {
+    type Value = OverpassMeta;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an overpass json response")
+    }
 
-    for way in response.elements {
-        // Ensure geometry exists
-        let geometry = way.geometry;
-        if !geometry.is_empty() {
-            features.push(MapFeature {
-                id: way.id.to_string(),
-                properties: way.tags.unwrap_or_default(),
-                geometry: geo::Polygon::new(geo::LineString(geometry.into_iter().map(|p| geo::Coord { x: p.lat, y: p.lon }).collect()), vec![]),
-            });
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut meta = OverpassMeta::default();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "elements" => map.next_value_seed(ElementStream { chunk_size: self.chunk_size, on_chunk: &mut *self.on_chunk })?,
+                "generator" => meta.generator = map.next_value()?,
+                "osm3s" => {
+                    if let Some(osm3s) = map.next_value::<Option<Osm3s>>()? {
+                        meta.timestamp_osm_base = osm3s.timestamp_osm_base;
+                        meta.copyright = osm3s.copyright;
+                    }
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
         }
+        Ok(meta)
     }
+}
 
+/// Reads the `elements` array, sending out features every `chunk_size` elements.
+struct ElementStream<'a, F> {
+    chunk_size: usize,
+    on_chunk: &'a mut F,
+}
 
-    Ok(features)
+impl<'de, F: FnMut(Vec<MapFeature>) -> bool> DeserializeSeed<'de> for ElementStream<'_, F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, F: FnMut(Vec<MapFeature>) -> bool> Visitor<'de> for ElementStream<'_, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a list of overpass elements")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut chunk = Vec::with_capacity(self.chunk_size);
+        while let Some(section) = seq.next_element::<Section>()? {
+            if let Some(feature) = section_to_feature(section) {
+                chunk.push(feature);
+            }
+            if chunk.len() >= self.chunk_size && !(self.on_chunk)(std::mem::replace(&mut chunk, Vec::with_capacity(self.chunk_size))) {
+                return Err(A::Error::custom("stream was cancelled"));
+            }
+        }
+        if !chunk.is_empty() && !(self.on_chunk)(chunk) {
+            return Err(A::Error::custom("stream was cancelled"));
+        }
+        Ok(())
+    }
 }
 
 /// Parses OSM data from a string and returns a vector of map features. This takes in geojson data.
@@ -37,8 +157,11 @@ pub fn get_map_data(file_path: &str) -> Result<Vec<MapFeature>, Box<dyn std::err
     // Parse the GeoJSON
     let geojson = GeoJson::from_reader(reader)?;
 
+    // GeoJSON positions are already longitude first
+    let to_lon_lats = |ring: Vec<Vec<f64>>| -> Vec<LonLat> { ring.into_iter().map(|p| LonLat::new(p[0], p[1])).collect() };
+
     let mut features = Vec::new();
-    let mut geo = geo::Polygon::new(geo::LineString(vec![]), vec![]);
+    let mut points = Vec::new();
     if let GeoJson::FeatureCollection(collection) = geojson {
         for feature in collection.features {
             if let Some(geometry) = feature.geometry {
@@ -46,30 +169,33 @@ pub fn get_map_data(file_path: &str) -> Result<Vec<MapFeature>, Box<dyn std::err
                 match geometry.value {
                     geojson::Value::Polygon(poly) => {
                         for ring in poly {
-                            
-                            geo = geo::Polygon::new(geo::LineString(ring.into_iter().map(|p| geo::Coord { x: p[0], y: p[1] }).collect()), vec![]);
+                            points = to_lon_lats(ring);
                         }
                     }
                     geojson::Value::LineString(line) => {
-                        geo = geo::Polygon::new(geo::LineString(line.into_iter().map(|p| geo::Coord { x: p[0], y: p[1] }).collect()), vec![]);
+                        points = to_lon_lats(line);
                     }
                     geojson::Value::MultiPolygon(multi_poly) => {
                         for poly in multi_poly {
                             for ring in poly {
-                                geo = geo::Polygon::new(geo::LineString(ring.into_iter().map(|p| geo::Coord { x: p[0], y: p[1] }).collect()), vec![]);
+                                points = to_lon_lats(ring);
                             }
                         }
                     }
                     _ => continue,
                 }
 
-                features.push(MapFeature {
-                    id: feature
-                        .id
-                        .map_or_else(|| String::from("unknown"), |id| format!("{:?}", id)),
-                    properties: serde_json::Value::Object(feature.properties.unwrap_or_default()),
-                    geometry: geo.clone(),
-                });
+                // Ids look like `way/123`, anything without one gets a negative id, the same as new elements in an OSM editor
+                let id = match &feature.id {
+                    Some(geojson::feature::Id::String(id)) => id.split_once('/').and_then(|(osm_type, id)| Some(FeatureId(OsmType::parse(osm_type)?, id.parse().ok()?))),
+                    Some(geojson::feature::Id::Number(id)) => id.as_i64().map(|id| FeatureId(OsmType::Way, id)),
+                    None => None,
+                };
+                features.push(MapFeature::from_lon_lats(
+                    id.unwrap_or(FeatureId(OsmType::Way, -(features.len() as i64) - 1)),
+                    serde_json::Value::Object(feature.properties.unwrap_or_default()),
+                    points.clone(),
+                ));
             }
         }
     }
@@ -79,15 +205,6 @@ pub fn get_map_data(file_path: &str) -> Result<Vec<MapFeature>, Box<dyn std::err
 
 
 // Overpass API, thanks to: https://transform.tools/json-to-rust-serde
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct OverpassResponse {
-    pub version: Option<f64>,
-    pub generator: Option<String>,
-    pub osm3s: Option<Osm3s>,
-    pub elements: Vec<Section>,
-}
-
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Osm3s {
@@ -129,4 +246,28 @@ struct Bounds {
 struct Geometry {
     pub lat: f64,
     pub lon: f64,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_osm_timestamps() {
+        assert_eq!(parse_osm_timestamp("1970-01-01T00:00:00Z"), Some(UNIX_EPOCH));
+        assert_eq!(parse_osm_timestamp("2025-01-10T12:34:56Z"), Some(UNIX_EPOCH + Duration::from_secs(1_736_512_496)));
+        // A leap day, and the end of a leap year
+        assert_eq!(parse_osm_timestamp("2024-02-29T00:00:00Z"), Some(UNIX_EPOCH + Duration::from_secs(1_709_164_800)));
+        assert_eq!(parse_osm_timestamp("2000-12-31T23:59:59Z"), Some(UNIX_EPOCH + Duration::from_secs(978_307_199)));
+    }
+
+    #[test]
+    fn rejects_bad_timestamps() {
+        assert_eq!(parse_osm_timestamp(""), None);
+        assert_eq!(parse_osm_timestamp("2025-01-10"), None);
+        assert_eq!(parse_osm_timestamp("2025-13-10T00:00:00Z"), None);
+        assert_eq!(parse_osm_timestamp("2025-01-32T00:00:00Z"), None);
+        assert_eq!(parse_osm_timestamp("2025-01-10T12:xx:56Z"), None);
+        // Before the unix epoch
+        assert_eq!(parse_osm_timestamp("1969-12-31T23:59:59Z"), None);
+    }
+}
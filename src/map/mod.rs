@@ -1,7 +1,21 @@
 mod types;
 mod projection;
 mod loader;
+mod diff;
+mod area;
+mod scale;
+mod utm;
+mod detail;
+mod roads;
+mod stylesheet;
 
 pub use types::*;
 pub use loader::*;
-pub use projection::*;
\ No newline at end of file
+pub use diff::*;
+pub use area::*;
+pub use projection::*;
+pub use scale::*;
+pub use utm::*;
+pub use detail::*;
+pub use roads::*;
+pub use stylesheet::*;
\ No newline at end of file
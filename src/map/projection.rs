@@ -1,8 +1,373 @@
-use std::f32::consts::PI;
+use std::{f64::consts::PI, sync::Arc};
 
-use bevy::math::Vec2;
+use bevy::{math::{DVec2, Vec2}, prelude::Resource};
+use rstar::AABB;
 
-use super::WorldSpaceRect;
+use super::{WorldSpaceRect, EARTH_RADIUS, SCALE, STARTING_LON_LAT};
+
+/// A way of flattening the earth onto the map. Points are projected onto a plane in metres,
+/// world space is then that plane relative to the world origin and scaled by `SCALE`.
+pub trait Projection: Send + Sync {
+    fn name(&self) -> String;
+
+    /// Where a point ends up on the plane, in metres.
+    fn forward(&self, point: LonLat) -> DVec2;
+
+    /// The point on the earth which is at a place on the plane.
+    fn inverse(&self, point: DVec2) -> LonLat;
+
+    /// How many metres on the plane a metre going east at this point is stretched to, 1 is true to scale.
+    fn scale_factor(&self, _point: LonLat) -> f64 {
+        1.0
+    }
+
+    /// The box on the earth which covers a box on the plane.
+    /// Straight lines on the plane can be curves on the earth, so the middles of the edges are checked as well as the corners.
+    fn inverse_rect(&self, min: DVec2, max: DVec2) -> LonLatRect {
+        let points = edge_points(min, max).map(|point| self.inverse(point));
+        LonLatRect::from_points(&points)
+    }
+
+    /// The box on the plane which covers a box on the earth, the opposite of `inverse_rect`.
+    fn forward_rect(&self, rect: LonLatRect) -> (DVec2, DVec2) {
+        let corners = edge_points(DVec2::new(rect.min.lon, rect.min.lat), DVec2::new(rect.max.lon, rect.max.lat));
+        let points = corners.map(|corner| self.forward(LonLat::new(corner.x, corner.y)));
+        let min = points.iter().fold(DVec2::INFINITY, |min, point| min.min(*point));
+        let max = points.iter().fold(DVec2::NEG_INFINITY, |max, point| max.max(*point));
+        (min, max)
+    }
+}
+
+/// The corners and the middles of the edges of a box.
+fn edge_points(min: DVec2, max: DVec2) -> [DVec2; 8] {
+    let mid = (min + max) / 2.0;
+    [
+        min, DVec2::new(mid.x, min.y), DVec2::new(max.x, min.y), DVec2::new(max.x, mid.y),
+        max, DVec2::new(mid.x, max.y), DVec2::new(min.x, max.y), DVec2::new(min.x, mid.y),
+    ]
+}
+
+/// The spherical Mercator used by web maps, this is what the map starts with.
+pub struct WebMercator;
+
+impl Projection for WebMercator {
+    fn name(&self) -> String {
+        "Web Mercator".to_string()
+    }
+
+    fn forward(&self, point: LonLat) -> DVec2 {
+        DVec2::new(EARTH_RADIUS * point.lon.to_radians(), EARTH_RADIUS * mercator_y(point.lat))
+    }
+
+    fn inverse(&self, point: DVec2) -> LonLat {
+        let lon = (point.x / EARTH_RADIUS).to_degrees();
+        let lat = (2.0 * (point.y / EARTH_RADIUS).exp().atan() - PI / 2.0).to_degrees();
+        LonLat::new(lon, lat)
+    }
+
+    fn scale_factor(&self, point: LonLat) -> f64 {
+        1.0 / point.lat.to_radians().cos()
+    }
+}
+
+/// An equirectangular projection centred somewhere, so a local east north up grid.
+/// Distances are true around the centre, which is what you want for surveying a small area.
+pub struct Local {
+    pub centre: LonLat,
+}
+
+impl Projection for Local {
+    fn name(&self) -> String {
+        format!("Local ({:.4}, {:.4})", self.centre.lat, self.centre.lon)
+    }
+
+    fn forward(&self, point: LonLat) -> DVec2 {
+        let x = EARTH_RADIUS * (point.lon - self.centre.lon).to_radians() * self.centre.lat.to_radians().cos();
+        let y = EARTH_RADIUS * (point.lat - self.centre.lat).to_radians();
+        DVec2::new(x, y)
+    }
+
+    fn inverse(&self, point: DVec2) -> LonLat {
+        let lon = self.centre.lon + (point.x / (EARTH_RADIUS * self.centre.lat.to_radians().cos())).to_degrees();
+        let lat = self.centre.lat + (point.y / EARTH_RADIUS).to_degrees();
+        LonLat::new(lon, lat)
+    }
+
+    fn scale_factor(&self, point: LonLat) -> f64 {
+        // North south is always true, east west only at the latitude of the centre
+        self.centre.lat.to_radians().cos() / point.lat.to_radians().cos()
+    }
+}
+
+/// Where world space is on the earth and how the earth is flattened onto it.
+/// The origin is moved to wherever the camera goes, so world coordinates stay small enough for f32 to draw them without jittering.
+/// Anything kept in world space has to be moved along with it, so most things are kept in lon and lat instead.
+#[derive(Resource, Clone)]
+pub struct WorldSpace {
+    pub origin: LonLat,
+    pub projection: Arc<dyn Projection>,
+    origin_on_plane: DVec2,     // Kept so the origin isn't projected again for every point
+}
+
+impl WorldSpace {
+    pub fn new(origin: LonLat, projection: Arc<dyn Projection>) -> Self {
+        let origin_on_plane = projection.forward(origin);
+        WorldSpace { origin, projection, origin_on_plane }
+    }
+
+    /// The same projection centred on `origin` instead.
+    pub fn with_origin(&self, origin: LonLat) -> Self {
+        WorldSpace::new(origin, self.projection.clone())
+    }
+
+    /// The same origin with a different projection.
+    pub fn with_projection(&self, projection: Arc<dyn Projection>) -> Self {
+        WorldSpace::new(self.origin, projection)
+    }
+
+    pub fn to_world(&self, point: LonLat) -> WorldPos {
+        self.plane_to_world(self.projection.forward(point))
+    }
+
+    pub fn to_lon_lat(&self, pos: WorldPos) -> LonLat {
+        self.projection.inverse(self.world_to_plane(pos))
+    }
+
+    pub fn rect_to_world(&self, rect: LonLatRect) -> WorldSpaceRect {
+        let (min, max) = self.projection.forward_rect(rect);
+        WorldSpaceRect::from_corners(self.plane_to_world(min), self.plane_to_world(max))
+    }
+
+    pub fn rect_to_lon_lat(&self, rect: &WorldSpaceRect) -> LonLatRect {
+        let min = self.world_to_plane(WorldPos::new(rect.left, rect.bottom));
+        let max = self.world_to_plane(WorldPos::new(rect.right, rect.top));
+        self.projection.inverse_rect(min, max)
+    }
+
+    fn plane_to_world(&self, point: DVec2) -> WorldPos {
+        // Taking the offset before going to f32 is what keeps everything precise
+        let pos = (point - self.origin_on_plane) * (SCALE / EARTH_RADIUS);
+        WorldPos::new(pos.x as f32, pos.y as f32)
+    }
+
+    fn world_to_plane(&self, pos: WorldPos) -> DVec2 {
+        self.origin_on_plane + DVec2::new(pos.x as f64, pos.y as f64) * (EARTH_RADIUS / SCALE)
+    }
+}
+
+impl Default for WorldSpace {
+    fn default() -> Self {
+        WorldSpace::new(STARTING_LON_LAT, Arc::new(WebMercator))
+    }
+}
+
+/// Moves things from one world space to another, for when the projection changes.
+pub struct Reprojection {
+    pub from: WorldSpace,
+    pub to: WorldSpace,
+}
+
+impl Reprojection {
+    pub fn point(&self, pos: Vec2) -> Vec2 {
+        self.to.to_world(self.from.to_lon_lat(pos.into())).into()
+    }
+}
+
+/// A point on the earth in degrees. Longitude comes first, the same as GeoJSON and `geo`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LonLat {
+    pub lon: f64,
+    pub lat: f64,
+}
+
+impl LonLat {
+    pub const fn new(lon: f64, lat: f64) -> Self {
+        LonLat { lon, lat }
+    }
+}
+
+// Features keep their geometry as `geo` coords, x is always the longitude
+impl From<LonLat> for geo::Coord {
+    fn from(point: LonLat) -> Self {
+        geo::Coord { x: point.lon, y: point.lat }
+    }
+}
+
+impl From<geo::Coord> for LonLat {
+    fn from(coord: geo::Coord) -> Self {
+        LonLat::new(coord.x, coord.y)
+    }
+}
+
+/// A point in world space, which is what everything on the map is drawn in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WorldPos {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl WorldPos {
+    pub const fn new(x: f32, y: f32) -> Self {
+        WorldPos { x, y }
+    }
+}
+
+impl From<Vec2> for WorldPos {
+    fn from(v: Vec2) -> Self {
+        WorldPos::new(v.x, v.y)
+    }
+}
+
+impl From<WorldPos> for Vec2 {
+    fn from(pos: WorldPos) -> Self {
+        Vec2::new(pos.x, pos.y)
+    }
+}
+
+/// A box on the earth in degrees, this is what overpass is asked for and what the data sources cover.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LonLatRect {
+    pub min: LonLat,    // South west corner
+    pub max: LonLat,    // North east corner
+}
+
+// Boxes are used as keys for the regions which have been loaded, which are never NaN
+impl Eq for LonLatRect {}
+
+impl std::hash::Hash for LonLatRect {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        [self.min.lon, self.min.lat, self.max.lon, self.max.lat].map(f64::to_bits).hash(state);
+    }
+}
+
+impl LonLatRect {
+    /// The box between two opposite corners, whichever way round they are.
+    pub fn from_corners(a: LonLat, b: LonLat) -> Self {
+        LonLatRect {
+            min: LonLat::new(a.lon.min(b.lon), a.lat.min(b.lat)),
+            max: LonLat::new(a.lon.max(b.lon), a.lat.max(b.lat)),
+        }
+    }
+
+    /// The smallest box around all of `points`.
+    pub fn from_points(points: &[LonLat]) -> Self {
+        let min = points.iter().fold(LonLat::new(f64::INFINITY, f64::INFINITY), |min, p| LonLat::new(min.lon.min(p.lon), min.lat.min(p.lat)));
+        let max = points.iter().fold(LonLat::new(f64::NEG_INFINITY, f64::NEG_INFINITY), |max, p| LonLat::new(max.lon.max(p.lon), max.lat.max(p.lat)));
+        LonLatRect { min, max }
+    }
+
+    pub fn from_envelope(envelope: &AABB<[f64; 2]>) -> Self {
+        LonLatRect {
+            min: LonLat::new(envelope.lower()[0], envelope.lower()[1]),
+            max: LonLat::new(envelope.upper()[0], envelope.upper()[1]),
+        }
+    }
+
+    /// The same box as an envelope in the feature tree.
+    pub fn envelope(&self) -> AABB<[f64; 2]> {
+        AABB::from_corners([self.min.lon, self.min.lat], [self.max.lon, self.max.lat])
+    }
+
+    pub fn width(&self) -> f64 {
+        self.max.lon - self.min.lon
+    }
+
+    pub fn height(&self) -> f64 {
+        self.max.lat - self.min.lat
+    }
+
+    fn area(&self) -> f64 {
+        self.width() * self.height()
+    }
+
+    /// Whether all of `rect` is inside of this one, allowing it to stick out by up to `margin` degrees.
+    pub fn contains(&self, rect: &LonLatRect, margin: f64) -> bool {
+        rect.min.lon >= self.min.lon - margin && rect.max.lon <= self.max.lon + margin
+            && rect.min.lat >= self.min.lat - margin && rect.max.lat <= self.max.lat + margin
+    }
+
+    /// Whether the two overlap, rather than only sharing an edge.
+    pub fn intersects(&self, rect: &LonLatRect) -> bool {
+        self.min.lon < rect.max.lon && rect.min.lon < self.max.lon && self.min.lat < rect.max.lat && rect.min.lat < self.max.lat
+    }
+
+    // This will split the current rect into the parts of it which aren't covered by any of `rects`.
+    pub fn split(&self, rects: &[LonLatRect]) -> Vec<LonLatRect> {
+        let mut result = vec![*self];
+        for rect in rects {
+            result = result.into_iter().flat_map(|r| r.split_single(rect).unwrap_or_else(|| vec![r])).collect();
+        }
+        result
+    }
+
+    /// Cuts `rect` out of this one, giving back up to four rects around where it was.
+    /// None means they don't overlap, and an empty vec means this one is completely covered.
+    pub fn split_single(&self, rect: &LonLatRect) -> Option<Vec<LonLatRect>> {
+        // Only the part of rect which is inside of this one matters
+        let inside = self.overlap(rect)?;
+
+        let mut result = Vec::new();
+        // The left and right go all the way up, the bottom and top fill in between them
+        if self.min.lon < inside.min.lon {
+            result.push(LonLatRect { min: self.min, max: LonLat::new(inside.min.lon, self.max.lat) });
+        }
+        if self.max.lon > inside.max.lon {
+            result.push(LonLatRect { min: LonLat::new(inside.max.lon, self.min.lat), max: self.max });
+        }
+        if self.min.lat < inside.min.lat {
+            result.push(LonLatRect { min: LonLat::new(inside.min.lon, self.min.lat), max: LonLat::new(inside.max.lon, inside.min.lat) });
+        }
+        if self.max.lat > inside.max.lat {
+            result.push(LonLatRect { min: LonLat::new(inside.min.lon, inside.max.lat), max: LonLat::new(inside.max.lon, self.max.lat) });
+        }
+        Some(result)
+    }
+
+    /// Where the two overlap, none if they don't.
+    fn overlap(&self, rect: &LonLatRect) -> Option<LonLatRect> {
+        self.intersects(rect).then(|| LonLatRect {
+            min: LonLat::new(self.min.lon.max(rect.min.lon), self.min.lat.max(rect.min.lat)),
+            max: LonLat::new(self.max.lon.min(rect.max.lon), self.max.lat.min(rect.max.lat)),
+        })
+    }
+
+    /// The smallest box which has all of `rects` in it.
+    pub fn union(rects: &[LonLatRect]) -> Option<LonLatRect> {
+        let corners: Vec<LonLat> = rects.iter().flat_map(|rect| [rect.min, rect.max]).collect();
+        (!corners.is_empty()).then(|| LonLatRect::from_points(&corners))
+    }
+
+    /// Joins up boxes which line up into bigger ones, such as a row of cells. Boxes are only joined when
+    /// the one they make covers nothing that they didn't, so this never takes in anywhere new.
+    pub fn merge_adjacent(rects: Vec<LonLatRect>) -> Vec<LonLatRect> {
+        let mut merged: Vec<LonLatRect> = Vec::new();
+        for mut rect in rects {
+            // Keep going, what it has become may line up with something it didn't before
+            while let Some(i) = merged.iter().position(|other| rect.lines_up_with(other)) {
+                let other = merged.swap_remove(i);
+                rect = LonLatRect::union(&[rect, other]).unwrap();
+            }
+            merged.push(rect);
+        }
+        merged
+    }
+
+    fn lines_up_with(&self, other: &LonLatRect) -> bool {
+        let touches = self.min.lon <= other.max.lon && other.min.lon <= self.max.lon && self.min.lat <= other.max.lat && other.min.lat <= self.max.lat;
+        if !touches {
+            return false;
+        }
+        let overlap = self.overlap(other).map_or(0.0, |overlap| overlap.area());
+        let union = LonLatRect::union(&[*self, *other]).unwrap();
+        // A little slack for edges which are a hair apart
+        union.area() <= (self.area() + other.area() - overlap) * 1.01
+    }
+
+    /// The box as an overpass bbox filter, which goes south, west, north, east.
+    pub fn overpass_bbox(&self) -> String {
+        format!("{},{},{},{}", self.min.lat, self.min.lon, self.max.lat, self.max.lon)
+    }
+}
 
 pub fn lat_lon_to_tile_mercator(lat_deg: f64, lon_deg: f64, zoom: i32) -> (i32, i32) {
     let n = (1 << zoom) as f64;
@@ -10,7 +375,7 @@ pub fn lat_lon_to_tile_mercator(lat_deg: f64, lon_deg: f64, zoom: i32) -> (i32,
     let x_tile = (n * (lon_deg + 180.0) / 360.0) as i32;
 
     let lat_rad = lat_deg.to_radians();
-    let y_tile = (n * (1.0 - (lat_rad.tan() + (1.0 / lat_rad.cos())).ln() / PI as f64) / 2.0) as i32;
+    let y_tile = (n * (1.0 - (lat_rad.tan() + (1.0 / lat_rad.cos())).ln() / PI) / 2.0) as i32;
 
     (x_tile, y_tile)
 } 
@@ -20,59 +385,20 @@ pub fn tile_to_lat_lon(x_tile: i32, y_tile: i32, zoom: i32) -> (f64, f64) {
 
     let lon_deg = x_tile as f64 / n * 360.0 - 180.0;
 
-    let lat_rad = (std::f64::consts::PI * (1.0 - 2.0 * y_tile as f64 / n)).sinh().atan();
+    let lat_rad = (PI * (1.0 - 2.0 * y_tile as f64 / n)).sinh().atan();
     let lat_deg = lat_rad.to_degrees();
 
     (lat_deg, lon_deg)
 }
 
-pub fn lat_lon_to_world_mercator(lat: f32, lon: f32, scale: f32, ref_long: f32, ref_lat: f32) -> Vec2 {
-    // Get an offset
-    let offset_long = lon - ref_long;
-    let offset_lat = lat - ref_lat;
-
-    // Apply the projection
-    let x = scale * offset_long.to_radians();
-    let y = scale * (std::f32::consts::PI / 4.0 + offset_lat.to_radians() / 2.0).tan().ln();
-
-    Vec2::new(x, y)
-} 
-
-pub fn world_mercator_to_lat_lon(x: f32, y: f32, scale: f32, ref_long: f32, ref_lat: f32) -> (f32, f32) {
-    // Reverse the projection for longitude
-    let lon = x / scale;
-    
-    // Reverse the projection for latitude
-    let lat = (2.0 * ((y / scale).exp().atan()) - std::f32::consts::PI / 2.0).to_degrees();
-    
-    // Add the offsets back to reference longitude and latitude
-    let lon = lon.to_degrees() + ref_long;
-    let lat = lat + ref_lat;
-
-    (lat, lon)
-}
-
-pub fn world_space_rect_to_lat_long(rect: WorldSpaceRect, scale: f32, ref_long: f32, ref_lat: f32) -> WorldSpaceRect {
-    
-        let (lat1, lon1) = world_mercator_to_lat_lon(rect.right, rect.bottom, scale, ref_long, ref_lat);
-        let (lat2, lon2) = world_mercator_to_lat_lon(rect.left, rect.top, scale, ref_long, ref_lat);
-    
-        
-        // this gives { left: 0.13576442, right: 0.14162578, bottom: 52.197693, top: 52.19229 }
-    WorldSpaceRect {
-        left: lon1,
-        right: lon2,
-        bottom: lat1,
-        top: lat2,
-    }
+/// How far up a Mercator map a latitude is, in radians.
+fn mercator_y(lat: f64) -> f64 {
+    (PI / 4.0 + lat.to_radians() / 2.0).tan().ln()
 }
 
-pub fn bounding_box_to_tiles(bbox: WorldSpaceRect, zoom: i32) -> Vec<(i32, i32)> {
-    let (min_lat, min_lon) = (bbox.bottom, bbox.left);
-    let (max_lat, max_lon) = (bbox.top, bbox.right);
-
-    let (min_x_tile, min_y_tile) = lat_lon_to_tile_mercator(min_lat as f64, min_lon as f64, zoom);
-    let (max_x_tile, max_y_tile) = lat_lon_to_tile_mercator(max_lat as f64, max_lon as f64, zoom);
+pub fn bounding_box_to_tiles(bbox: LonLatRect, zoom: i32) -> Vec<(i32, i32)> {
+    let (min_x_tile, min_y_tile) = lat_lon_to_tile_mercator(bbox.min.lat, bbox.min.lon, zoom);
+    let (max_x_tile, max_y_tile) = lat_lon_to_tile_mercator(bbox.max.lat, bbox.max.lon, zoom);
 
     let mut tiles = Vec::new();
     for x in min_x_tile..=max_x_tile {
@@ -82,4 +408,48 @@ pub fn bounding_box_to_tiles(bbox: WorldSpaceRect, zoom: i32) -> Vec<(i32, i32)>
     }
 
     tiles
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::Utm;
+
+    // Both hemispheres, either side of the prime meridian and near the edge of a UTM zone
+    const POINTS: [LonLat; 5] = [
+        STARTING_LON_LAT,
+        LonLat::new(151.2093, -33.8688),
+        LonLat::new(-78.4678, -0.1807),
+        LonLat::new(-122.4194, 37.7749),
+        LonLat::new(11.99, 60.5),
+    ];
+
+    fn assert_round_trips(projection: &dyn Projection, point: LonLat) {
+        let back = projection.inverse(projection.forward(point));
+        // A ten millionth of a degree is about a centimetre
+        assert!((back.lon - point.lon).abs() < 1e-7 && (back.lat - point.lat).abs() < 1e-7,
+            "{} took {:?} to {:?}", projection.name(), point, back);
+    }
+
+    #[test]
+    fn web_mercator_round_trips() {
+        for point in POINTS {
+            assert_round_trips(&WebMercator, point);
+        }
+    }
+
+    #[test]
+    fn utm_round_trips() {
+        for point in POINTS {
+            assert_round_trips(&Utm::for_point(point), point);
+        }
+    }
+
+    #[test]
+    fn world_space_round_trips_near_the_origin() {
+        let world_space = WorldSpace::new(STARTING_LON_LAT, Arc::new(Utm::for_point(STARTING_LON_LAT)));
+        let point = LonLat::new(STARTING_LON_LAT.lon + 0.01, STARTING_LON_LAT.lat - 0.01);
+        let back = world_space.to_lon_lat(world_space.to_world(point));
+        // World space is f32, which is still good to a few centimetres this close to the origin
+        assert!((back.lon - point.lon).abs() < 1e-6 && (back.lat - point.lat).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,96 @@
+use super::MapFeature;
+
+/// How wide a lane is taken to be when a road only says how many it has.
+const LANE_WIDTH: f64 = 3.0;
+/// Standard gauge track with a bit of ballast either side.
+const RAILWAY_WIDTH: f64 = 3.0;
+
+/// How wide a road or railway is on the ground in metres, none if the feature isn't one.
+/// Goes by the `width` tag, then `est_width`, then how many `lanes` it has, then what is usual for its class.
+pub fn way_width(feature: &MapFeature) -> Option<f64> {
+    let tag = |key: &str| feature.properties.get(key).and_then(|v| v.as_str());
+    if tag("railway").is_some() {
+        return Some(tag("width").and_then(parse_width).unwrap_or(RAILWAY_WIDTH));
+    }
+    let class = tag("highway")?;
+
+    let lanes = tag("lanes")
+        .and_then(|lanes| lanes.trim().parse::<f64>().ok())
+        .filter(|lanes| *lanes > 0.0)
+        .map(|lanes| lanes * LANE_WIDTH);
+    Some(tag("width").and_then(parse_width)
+        .or_else(|| tag("est_width").and_then(parse_width))
+        .or(lanes)
+        .unwrap_or_else(|| class_width(class)))
+}
+
+/// The usual carriageway width of a highway class, for roads which don't say how wide they are.
+fn class_width(class: &str) -> f64 {
+    match class.trim_end_matches("_link") {
+        "motorway" => 14.0,
+        "trunk" => 12.0,
+        "primary" => 10.0,
+        "secondary" => 8.0,
+        "tertiary" => 7.0,
+        "unclassified" | "residential" => 6.0,
+        "living_street" | "pedestrian" | "busway" | "bus_guideway" => 5.0,
+        "service" | "raceway" => 4.0,
+        "track" => 3.0,
+        "footway" | "path" | "cycleway" | "bridleway" | "steps" | "corridor" => 2.0,
+        _ => 4.0,
+    }
+}
+
+/// Reads a width the way OSM tags write them, which is metres unless it says otherwise,
+/// so "7", "7.5 m", "7,5" and "12'6\"" all work.
+fn parse_width(value: &str) -> Option<f64> {
+    let value = value.trim().replace(',', ".");
+    if let Some((feet, inches)) = value.split_once('\'') {
+        let feet: f64 = feet.trim().parse().ok()?;
+        let inches: f64 = inches.trim().trim_end_matches('"').trim().parse().unwrap_or(0.0);
+        return Some((feet + inches / 12.0) * 0.3048);
+    }
+    let (number, unit) = value.split_at(value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len()));
+    let number: f64 = number.parse().ok()?;
+    let metres = match unit.trim() {
+        "" | "m" => number,
+        "km" => number * 1000.0,
+        "ft" => number * 0.3048,
+        "mi" => number * 1609.344,
+        _ => return None,
+    };
+    (metres > 0.0).then_some(metres)
+}
+
+/// Whether a feature is a road or railway, which are lines rather than outlines even though they are kept as polygons like everything else.
+/// Squares and other areas tagged `area=yes` are outlines.
+pub fn is_line(feature: &MapFeature) -> bool {
+    let tag = |key: &str| feature.properties.get(key).and_then(|v| v.as_str());
+    (tag("highway").is_some() || tag("railway").is_some()) && tag("area") != Some("yes")
+}
+
+/// Whether a way is a bridge, anything other than `bridge=no` counts.
+pub fn is_bridge(feature: &MapFeature) -> bool {
+    feature.properties.get("bridge").and_then(|v| v.as_str()).is_some_and(|v| v != "no")
+}
+
+/// Whether a way is in a tunnel, which includes culverts and passages through buildings.
+pub fn is_tunnel(feature: &MapFeature) -> bool {
+    feature.properties.get("tunnel").and_then(|v| v.as_str()).is_some_and(|v| v != "no")
+}
+
+/// Which layer a way is drawn on from its `layer` tag, bridges without one go above the ground and tunnels below it.
+/// Kept within -5 to 5, which is what the OSM wiki says is valid.
+pub fn way_layer(feature: &MapFeature) -> i32 {
+    let layer = feature.properties.get("layer")
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.trim().parse::<i32>().ok());
+    let default = if is_bridge(feature) {
+        1
+    } else if is_tunnel(feature) {
+        -1
+    } else {
+        0
+    };
+    layer.unwrap_or(default).clamp(-5, 5)
+}
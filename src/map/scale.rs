@@ -0,0 +1,60 @@
+use std::f64::consts::PI;
+
+use super::{LonLat, WorldSpace, SCALE};
+
+/// The radius Web Mercator uses for the earth, in metres.
+pub const EARTH_RADIUS: f64 = 6_378_137.0;
+/// The size of a slippy map tile in pixels, zoom levels are worked out as if the map was made of these.
+const TILE_SIZE: f64 = 256.0;
+
+/// The most the camera can zoom in, about as close as the OSM website lets you go.
+pub const MAX_ZOOM: f64 = 20.0;
+
+/// How many metres on the ground one unit of world space covers going east from a point.
+/// Most projections stretch things the further they are from where they are true to scale, Mercator more so the further north or south.
+pub fn metres_per_world_unit(point: LonLat, world_space: &WorldSpace) -> f64 {
+    EARTH_RADIUS / SCALE / world_space.projection.scale_factor(point)
+}
+
+/// How many metres on the ground a pixel covers at a point, with the camera zoomed to `camera_scale`.
+pub fn metres_per_pixel(camera_scale: f32, point: LonLat, world_space: &WorldSpace) -> f64 {
+    camera_scale as f64 * metres_per_world_unit(point, world_space)
+}
+
+/// How many metres a pixel of a slippy map tile covers at a zoom level, at a latitude.
+pub fn metres_per_tile_pixel(zoom: f64, lat: f64) -> f64 {
+    // A tile at zoom 0 covers the whole way round the earth, at the latitude it is shrunk by Mercator
+    2.0 * PI * EARTH_RADIUS * lat.to_radians().cos() / TILE_SIZE / 2f64.powf(zoom)
+}
+
+/// The slippy map zoom level which shows the ground at the same scale as the camera does at a point,
+/// so 0 is the whole world on one tile and 18 is street level. With Mercator this is the same everywhere.
+pub fn zoom_level(camera_scale: f32, point: LonLat, world_space: &WorldSpace) -> f64 {
+    (metres_per_tile_pixel(0.0, point.lat) / metres_per_pixel(camera_scale, point, world_space)).log2()
+}
+
+/// The camera scale which shows a zoom level at a point, the opposite of `zoom_level`.
+pub fn camera_scale_for_zoom(zoom: f64, point: LonLat, world_space: &WorldSpace) -> f32 {
+    (metres_per_tile_pixel(zoom, point.lat) / metres_per_world_unit(point, world_space)) as f32
+}
+
+/// The longest round distance, 1, 2 or 5 times a power of ten metres, which fits in `max_pixels`.
+/// Gives back the distance in metres and how many pixels long it is.
+pub fn scale_bar_length(metres_per_pixel: f64, max_pixels: f64) -> (f64, f64) {
+    let max_metres = metres_per_pixel * max_pixels;
+    let magnitude = 10f64.powf(max_metres.log10().floor());
+    let metres = [5.0, 2.0, 1.0].into_iter()
+        .map(|step| step * magnitude)
+        .find(|metres| *metres <= max_metres)
+        .unwrap_or(magnitude);
+    (metres, metres / metres_per_pixel)
+}
+
+/// Writes a distance the way a scale bar would, in metres until it gets to a kilometre.
+pub fn format_distance(metres: f64) -> String {
+    if metres >= 1000.0 {
+        format!("{} km", metres / 1000.0)
+    } else {
+        format!("{} m", metres)
+    }
+}
@@ -0,0 +1,372 @@
+use bevy::color::Srgba;
+
+use super::{MapFeature, OsmType};
+
+/// A map style written in a subset of MapCSS, see `assets/style.mapcss` for what it looks like.
+/// Rules are applied in order, so later ones override what earlier ones set for the same feature.
+///
+/// Selectors are `way`, `area`, `relation`, `node` or `*`, then an optional zoom range like `|z14-16`, `|z15-` or `|z-12`,
+/// then any number of tag tests, `[key]`, `[!key]`, `[key=value]` and `[key!=value]`. `[key]` is true for any value but `no`,
+/// so `[bridge]` doesn't pick up `bridge=no`. Every feature here is an outline, so `way` and `area` both match ways and relations.
+#[derive(Default)]
+pub struct Stylesheet {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    selectors: Vec<Selector>,
+    declarations: Vec<(String, StyleValue)>,
+}
+
+struct Selector {
+    element: Option<OsmType>,   // None matches ways and relations, `*` matches everything
+    any: bool,
+    min_zoom: f64,
+    max_zoom: f64,
+    tests: Vec<TagTest>,
+}
+
+enum TagTest {
+    Has(String),
+    NotHas(String),
+    Equals(String, String),
+    NotEquals(String, String),
+}
+
+/// A value from a declaration, parsed as whatever the property it was for needs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StyleValue {
+    Color(StyleColor),
+    Number(f32),
+    Width(StyleWidth),
+    Dashes(Vec<f32>),
+    Text(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StyleColor {
+    Rgba(Srgba),
+    Layer,  // The color picked for the feature's layer in the settings
+    None,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StyleWidth {
+    Pixels(f32),
+    Metres(f32),
+    Auto,   // Worked out from the tags, for roads and railways
+}
+
+/// Everything the rules set for one feature, anything they don't mention is left as none.
+#[derive(Clone, Debug, Default)]
+pub struct ComputedStyle {
+    pub fill_color: Option<StyleColor>,
+    pub fill_opacity: Option<f32>,
+    pub color: Option<StyleColor>,
+    pub opacity: Option<f32>,
+    pub width: Option<StyleWidth>,
+    pub dashes: Option<Vec<f32>>,
+    pub casing_width: Option<StyleWidth>,
+    pub casing_color: Option<StyleColor>,
+    pub casing_opacity: Option<f32>,
+    pub z_index: Option<f32>,
+    pub text: Option<String>,
+    pub text_color: Option<StyleColor>,
+    pub font_size: Option<f32>,
+}
+
+impl Stylesheet {
+    pub fn parse(source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let source = strip_comments(source);
+        let mut rules = Vec::new();
+        let mut rest = source.as_str();
+        while !rest.trim().is_empty() {
+            let open = rest.find('{').ok_or_else(|| format!("expected `{{` after `{}`", rest.trim()))?;
+            let close = rest[open..].find('}').map(|close| open + close).ok_or("a rule is missing its closing `}`")?;
+            let head = rest[..open].trim();
+            let selectors = split_top_level(head, ',')
+                .into_iter()
+                .map(|selector| parse_selector(selector.trim()).map_err(|e| format!("in `{}`: {}", head, e)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let declarations = rest[open + 1..close]
+                .split(';')
+                .filter(|declaration| !declaration.trim().is_empty())
+                .map(|declaration| parse_declaration(declaration).map_err(|e| format!("in `{}`: {}", head, e)))
+                .collect::<Result<Vec<_>, _>>()?;
+            rules.push(Rule { selectors, declarations });
+            rest = &rest[close + 1..];
+        }
+        Ok(Stylesheet { rules })
+    }
+
+    /// Runs every rule which matches the feature at a zoom level, in order.
+    pub fn style(&self, feature: &MapFeature, zoom: f64) -> ComputedStyle {
+        let mut style = ComputedStyle::default();
+        for rule in &self.rules {
+            if !rule.selectors.iter().any(|selector| selector.matches(feature, zoom)) {
+                continue;
+            }
+            for (property, value) in &rule.declarations {
+                style.set(property, value.clone());
+            }
+        }
+        style
+    }
+}
+
+impl Selector {
+    fn matches(&self, feature: &MapFeature, zoom: f64) -> bool {
+        let element = match self.element {
+            _ if self.any => true,
+            Some(element) => feature.id.0 == element,
+            None => feature.id.0 != OsmType::Node,
+        };
+        element && zoom >= self.min_zoom && zoom < self.max_zoom && self.tests.iter().all(|test| test.matches(feature))
+    }
+}
+
+impl TagTest {
+    fn matches(&self, feature: &MapFeature) -> bool {
+        let tag = |key: &str| feature.properties.get(key).and_then(|v| v.as_str());
+        match self {
+            TagTest::Has(key) => tag(key).is_some_and(|v| v != "no"),
+            TagTest::NotHas(key) => tag(key).is_none_or(|v| v == "no"),
+            TagTest::Equals(key, value) => tag(key) == Some(value),
+            TagTest::NotEquals(key, value) => tag(key) != Some(value),
+        }
+    }
+}
+
+impl ComputedStyle {
+    fn set(&mut self, property: &str, value: StyleValue) {
+        match (property, value) {
+            ("fill-color", StyleValue::Color(color)) => self.fill_color = Some(color),
+            ("fill-opacity", StyleValue::Number(n)) => self.fill_opacity = Some(n),
+            ("color", StyleValue::Color(color)) => self.color = Some(color),
+            ("opacity", StyleValue::Number(n)) => self.opacity = Some(n),
+            ("width", StyleValue::Width(width)) => self.width = Some(width),
+            ("dashes", StyleValue::Dashes(dashes)) => self.dashes = Some(dashes),
+            ("casing-width", StyleValue::Width(width)) => self.casing_width = Some(width),
+            ("casing-color", StyleValue::Color(color)) => self.casing_color = Some(color),
+            ("casing-opacity", StyleValue::Number(n)) => self.casing_opacity = Some(n),
+            ("z-index", StyleValue::Number(n)) => self.z_index = Some(n),
+            ("text", StyleValue::Text(key)) => self.text = Some(key),
+            ("text-color", StyleValue::Color(color)) => self.text_color = Some(color),
+            ("font-size", StyleValue::Number(n)) => self.font_size = Some(n),
+            _ => {}     // The parser only lets through values which fit the property
+        }
+    }
+}
+
+fn strip_comments(source: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = rest[start + 2..].find("*/").map_or("", |end| &rest[start + 2 + end + 2..]);
+    }
+    out.push_str(rest);
+    out.lines()
+        .map(|line| if line.trim_start().starts_with("//") { "" } else { line })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Splits on `separator` where it isn't inside square brackets.
+fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn parse_selector(text: &str) -> Result<Selector, Box<dyn std::error::Error>> {
+    let name_end = text.find(['|', '[']).unwrap_or(text.len());
+    let (element, any) = match text[..name_end].trim() {
+        "*" => (None, true),
+        "way" | "area" => (None, false),
+        "relation" => (Some(OsmType::Relation), false),
+        "node" => (Some(OsmType::Node), false),
+        other => return Err(format!("unknown element `{}`", other).into()),
+    };
+    let mut selector = Selector { element, any, min_zoom: f64::NEG_INFINITY, max_zoom: f64::INFINITY, tests: Vec::new() };
+
+    let mut rest = text[name_end..].trim();
+    if let Some(zoom) = rest.strip_prefix("|z") {
+        let end = zoom.find('[').unwrap_or(zoom.len());
+        let range = zoom[..end].trim();
+        let (min, max) = range.split_once('-').unwrap_or((range, range));
+        if !min.is_empty() {
+            selector.min_zoom = min.parse()?;
+        }
+        if !max.is_empty() {
+            // Zoom ranges include the last zoom level, so z12-14 goes up to but not including 15
+            selector.max_zoom = max.parse::<f64>()? + 1.0;
+        }
+        rest = zoom[end..].trim();
+    }
+
+    while let Some(test) = rest.strip_prefix('[') {
+        let end = test.find(']').ok_or("a tag test is missing its `]`")?;
+        selector.tests.push(parse_tag_test(&test[..end])?);
+        rest = test[end + 1..].trim();
+    }
+    if !rest.is_empty() {
+        return Err(format!("don't know what to do with `{}`", rest).into());
+    }
+    Ok(selector)
+}
+
+fn parse_tag_test(text: &str) -> Result<TagTest, Box<dyn std::error::Error>> {
+    let unquote = |s: &str| s.trim().trim_matches('"').trim_matches('\'').to_string();
+    let test = if let Some(key) = text.trim().strip_prefix('!') {
+        TagTest::NotHas(unquote(key))
+    } else if let Some((key, value)) = text.split_once("!=") {
+        TagTest::NotEquals(unquote(key), unquote(value))
+    } else if let Some((key, value)) = text.split_once('=') {
+        TagTest::Equals(unquote(key), unquote(value))
+    } else {
+        TagTest::Has(unquote(text))
+    };
+    let (TagTest::Has(key) | TagTest::NotHas(key) | TagTest::Equals(key, _) | TagTest::NotEquals(key, _)) = &test;
+    if key.is_empty() {
+        return Err(format!("`[{}]` doesn't have a key", text).into());
+    }
+    Ok(test)
+}
+
+fn parse_declaration(text: &str) -> Result<(String, StyleValue), Box<dyn std::error::Error>> {
+    let (property, value) = text.split_once(':').ok_or_else(|| format!("`{}` should be `property: value`", text.trim()))?;
+    let (property, value) = (property.trim().to_lowercase(), value.trim());
+    let parsed = match property.as_str() {
+        "fill-color" | "color" | "casing-color" | "text-color" => StyleValue::Color(parse_color(value)?),
+        "fill-opacity" | "opacity" | "casing-opacity" => match value.parse()? {
+            n if (0.0..=1.0).contains(&n) => StyleValue::Number(n),
+            _ => return Err(format!("{} has to be between 0 and 1, not `{}`", property, value).into()),
+        },
+        "font-size" => match value.parse()? {
+            n if n > 0.0 => StyleValue::Number(n),
+            _ => return Err(format!("font-size has to be more than 0, not `{}`", value).into()),
+        },
+        "z-index" => StyleValue::Number(value.parse()?),
+        "width" | "casing-width" => StyleValue::Width(parse_width(value)?),
+        "dashes" => {
+            let dashes: Vec<f32> = value.split(',').map(|dash| dash.trim().parse()).collect::<Result<_, _>>()?;
+            if !dashes.iter().all(|dash| *dash >= 0.0) {
+                return Err(format!("dashes can't be negative, `{}`", value).into());
+            }
+            StyleValue::Dashes(dashes)
+        }
+        "text" => StyleValue::Text(value.trim_matches('"').trim_matches('\'').to_string()),
+        _ => return Err(format!("unknown property `{}`", property).into()),
+    };
+    Ok((property, parsed))
+}
+
+fn parse_width(value: &str) -> Result<StyleWidth, Box<dyn std::error::Error>> {
+    let width = if value == "auto" {
+        StyleWidth::Auto
+    } else if let Some(metres) = value.strip_suffix('m') {
+        StyleWidth::Metres(metres.trim().parse()?)
+    } else {
+        StyleWidth::Pixels(value.trim_end_matches("px").trim().parse()?)
+    };
+    match width {
+        StyleWidth::Pixels(n) | StyleWidth::Metres(n) if !n.is_finite() || n < 0.0 => Err(format!("widths can't be negative or infinite, `{}`", value).into()),
+        width => Ok(width),
+    }
+}
+
+fn parse_color(value: &str) -> Result<StyleColor, Box<dyn std::error::Error>> {
+    let value = value.to_lowercase();
+    let named = match value.as_str() {
+        "layer" => return Ok(StyleColor::Layer),
+        "none" | "transparent" => return Ok(StyleColor::None),
+        "black" => "#000000",
+        "white" => "#ffffff",
+        "gray" | "grey" => "#808080",
+        "red" => "#ff0000",
+        "green" => "#008000",
+        "blue" => "#0000ff",
+        "yellow" => "#ffff00",
+        "orange" => "#ffa500",
+        other => other,
+    };
+    Srgba::hex(named).map(StyleColor::Rgba).map_err(|_| format!("`{}` isn't a color", value).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{FeatureId, LonLat};
+
+    fn way(tags: serde_json::Value) -> MapFeature {
+        MapFeature::from_lon_lats(FeatureId(OsmType::Way, 1), tags, [LonLat::new(0.0, 0.0), LonLat::new(0.1, 0.0), LonLat::new(0.1, 0.1)])
+    }
+
+    #[test]
+    fn zoom_ranges_include_the_last_level() {
+        let stylesheet = Stylesheet::parse("way|z12-14 { width: 2 }").unwrap();
+        let feature = way(serde_json::json!({}));
+        assert_eq!(stylesheet.style(&feature, 11.9).width, None);
+        assert_eq!(stylesheet.style(&feature, 12.0).width, Some(StyleWidth::Pixels(2.0)));
+        assert_eq!(stylesheet.style(&feature, 14.9).width, Some(StyleWidth::Pixels(2.0)));
+        assert_eq!(stylesheet.style(&feature, 15.0).width, None);
+    }
+
+    #[test]
+    fn has_tag_skips_no() {
+        let stylesheet = Stylesheet::parse("way[bridge] { color: red } way[!bridge] { color: blue }").unwrap();
+        let red = Some(StyleColor::Rgba(Srgba::hex("#ff0000").unwrap()));
+        let blue = Some(StyleColor::Rgba(Srgba::hex("#0000ff").unwrap()));
+        assert_eq!(stylesheet.style(&way(serde_json::json!({"bridge": "yes"})), 15.0).color, red);
+        assert_eq!(stylesheet.style(&way(serde_json::json!({"bridge": "viaduct"})), 15.0).color, red);
+        assert_eq!(stylesheet.style(&way(serde_json::json!({"bridge": "no"})), 15.0).color, blue);
+        assert_eq!(stylesheet.style(&way(serde_json::json!({})), 15.0).color, blue);
+    }
+
+    #[test]
+    fn later_rules_override_earlier_ones() {
+        let stylesheet = Stylesheet::parse("way { width: 1; opacity: 0.5 } way[highway=primary] { width: 4m }").unwrap();
+        let style = stylesheet.style(&way(serde_json::json!({"highway": "primary"})), 15.0);
+        assert_eq!(style.width, Some(StyleWidth::Metres(4.0)));
+        assert_eq!(style.opacity, Some(0.5));
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        for source in [
+            "way { width: -1 }",
+            "way { casing-width: -2m }",
+            "way { width: inf }",
+            "way { width: NaN }",
+            "way { opacity: 1.5 }",
+            "way { fill-opacity: -0.1 }",
+            "way { font-size: 0 }",
+            "way { dashes: 4, -2 }",
+            "way { color: not-a-color }",
+            "way { glow: 3 }",
+            "way[] { width: 1 }",
+            "line { width: 1 }",
+            "way { width: 1",
+        ] {
+            assert!(Stylesheet::parse(source).is_err(), "`{}` should have been rejected", source);
+        }
+    }
+
+    #[test]
+    fn default_stylesheet_parses() {
+        Stylesheet::parse(include_str!("../../assets/style.mapcss")).unwrap();
+    }
+}
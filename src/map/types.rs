@@ -1,31 +1,88 @@
+use std::{collections::{HashMap, HashSet}, fmt, sync::{Arc, OnceLock}, time::{Duration, SystemTime}};
+
 use bevy::prelude::*;
-use geo::BoundingRect;
-use super::projection::lat_lon_to_world_mercator;
-use rstar::{RTree, RTreeObject, AABB};
+use geo::{BoundingRect, Contains};
+use super::{parse_osm_timestamp, LonLat, LonLatRect, WorldPos, DETAIL_LEVELS};
+use rstar::{Envelope, RTree, RTreeObject, SelectionFunction, AABB};
 
 // E.g Cambridge as the Starting point, make this a global entity/constant
-pub const STARTING_LONG_LAT: Vec2 = Vec2::new(0.1494117, 52.192_37);
-pub const SCALE: f32 = 10000000.0;
+pub const STARTING_LON_LAT: LonLat = LonLat::new(0.1494117, 52.192_37);
+pub const SCALE: f64 = 10000000.0;
+/// Anything thinner than this in degrees isn't worth asking overpass for, it is about a metre.
+const MIN_UNCOVERED_SIZE: f64 = 0.00001;
+/// More bits of a rect than this which need loading get fetched as one rect around all of them.
+const MAX_UNCOVERED_RECTS: usize = 8;
+
+/// The kind of OSM element a feature came from, ids are only unique within each one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OsmType {
+    Node,
+    Way,
+    Relation,
+}
+
+impl OsmType {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "node" | "n" => Some(OsmType::Node),
+            "way" | "w" => Some(OsmType::Way),
+            "relation" | "r" => Some(OsmType::Relation),
+            _ => None,
+        }
+    }
+}
+
+/// The id of a feature, this is the same as the OSM element it came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FeatureId(pub OsmType, pub i64);
+
+impl fmt::Display for FeatureId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let osm_type = match self.0 {
+            OsmType::Node => "node",
+            OsmType::Way => "way",
+            OsmType::Relation => "relation",
+        };
+        write!(f, "{}/{}", osm_type, self.1)
+    }
+}
 
 #[derive(Component, Clone, Debug)]
 pub struct MapFeature {
-    pub id: String,
+    pub id: FeatureId,
     pub properties: serde_json::Value,  // Use serde_json for flexible properties such as buidling type
     // Next make this a spacial hashmap, it becomes slower to check if a point is in a polygon the more there are
-    pub geometry: geo::Polygon    // Next make this a spacial hashmap
+    pub geometry: geo::Polygon,   // In lon and lat, so x is the longitude. Build it with `from_lon_lats` to keep it that way
+    pub detail: Arc<[OnceLock<Option<geo::Polygon>>; DETAIL_LEVELS]>,   // The simplified outlines, shared between clones so each is only worked out once
 }
 impl MapFeature {
-    pub fn get_in_world_space(&self) -> Vec<Vec2> {
-        let new_geo = self.geometry.clone();
-        let exterior = new_geo.exterior().clone();
-        let mut new_points = Vec::new();
-        for coord in exterior {
-            let point = lat_lon_to_world_mercator(coord.x as f32, coord.y as f32, SCALE, STARTING_LONG_LAT.x, STARTING_LONG_LAT.y);
-            new_points.push(point);
+    pub fn from_lon_lats(id: FeatureId, properties: serde_json::Value, points: impl IntoIterator<Item = LonLat>) -> Self {
+        MapFeature {
+            id,
+            properties,
+            geometry: geo::Polygon::new(geo::LineString(points.into_iter().map(geo::Coord::from).collect()), vec![]),
+            detail: Arc::default(),
         }
-        new_points
+    }
+
+    pub fn lon_lats(&self) -> impl Iterator<Item = LonLat> + '_ {
+        self.geometry.exterior().coords().map(|c| LonLat::from(*c))
+    }
+
+    /// Roughly how many bytes the feature takes up, this is what the memory budget goes by.
+    pub fn estimated_size(&self) -> usize {
+        let tags: usize = self.properties.as_object().map_or(0, |tags| {
+            // Each tag has the overhead of the map entry and the strings on top of what is in them
+            tags.iter().map(|(key, value)| key.len() + value.as_str().map_or(16, str::len) + 64).sum()
+        });
+        let points = self.geometry.exterior().0.len() * std::mem::size_of::<geo::Coord>();
+        // The levels of detail are cached as they are drawn, so they are counted up front to keep the total from drifting.
+        // Each is simplified more than the one after it, so together they come to about one more copy of the outline
+        let detail = std::mem::size_of::<[OnceLock<Option<geo::Polygon>>; DETAIL_LEVELS]>() + points;
+        std::mem::size_of::<MapFeature>() + points + detail + tags
     }
 }
+// The envelope is [lon, lat], the same as `LonLatRect::envelope`
 impl RTreeObject for MapFeature {
     type Envelope = AABB<[f64; 2]>;
 
@@ -35,6 +92,22 @@ impl RTreeObject for MapFeature {
     }
 }
 
+/// Finds a feature with a certain id, only looking in the parts of the tree which could hold its envelope.
+pub struct FeatureIdSelection {
+    pub id: FeatureId,
+    pub envelope: AABB<[f64; 2]>,
+}
+
+impl SelectionFunction<MapFeature> for FeatureIdSelection {
+    fn should_unpack_parent(&self, envelope: &AABB<[f64; 2]>) -> bool {
+        envelope.contains_envelope(&self.envelope)
+    }
+
+    fn should_unpack_leaf(&self, leaf: &MapFeature) -> bool {
+        leaf.id == self.id
+    }
+}
+
 /*
 #[derive(Component, Clone, Debug, PartialEq)]
 pub struct MapFeature {
@@ -85,21 +158,6 @@ fn polygon_area(geometry: &Vec<Vec2>) -> f32 {
     area
 }
 
-#[derive(Component, Clone, Debug)]
-pub struct RefrencePoint {
-    pub long: f32, // Longitude of the map's reference point
-    pub lat: f32,  // Latitude of the map's reference point
-}
-
-impl RefrencePoint {
-    pub fn new(long: f32, lat: f32) -> Self {
-        Self { long, lat }
-    }
-
-    pub fn get_long_lat(&self) -> Vec2 {
-        Vec2::new(self.long, self.lat)
-    }
-}
 #[derive(Component, Clone, Debug, PartialEq)]
 pub struct WorldSpaceRect {
     pub left: f32,
@@ -109,87 +167,79 @@ pub struct WorldSpaceRect {
 }
 
 impl WorldSpaceRect {
-    // This will split the current rect into multiple rects, it really struggles with getting anything if it is overflowing to the left.
-    pub fn split(&self, rects: Vec<WorldSpaceRect>) -> Option<Vec<WorldSpaceRect>> {
-        let mut result = vec![self.clone()];
-
-        for rect in rects {
-            let mut new_result = Vec::new();
-            for r in result {
-                if let Some(mut split_rects) = r.split_single(&rect) {
-                    new_result.append(&mut split_rects);
-                } else {
-                    new_result.push(r);
-                }
-            }
-            result = new_result;
+    /// The rect between two opposite corners, whichever way round they are.
+    pub fn from_corners(a: WorldPos, b: WorldPos) -> Self {
+        WorldSpaceRect {
+            left: a.x.min(b.x),
+            right: a.x.max(b.x),
+            bottom: a.y.min(b.y),
+            top: a.y.max(b.y),
         }
+    }
 
-        Some(result)
+    pub fn center(&self) -> WorldPos {
+        WorldPos::new((self.left + self.right) / 2.0, (self.bottom + self.top) / 2.0)
     }
 
-    pub fn split_single(&self, rect: &WorldSpaceRect) -> Option<Vec<WorldSpaceRect>> {
-        let mut result = Vec::new();
+    pub fn width(&self) -> f32 {
+        self.right - self.left
+    }
 
-        // Add the left region
-        if self.left < rect.left {
-            result.push(WorldSpaceRect {
-                left: self.left,
-                right: rect.left,
-                bottom: self.bottom,
-                top: self.top,
-            });
-        }
+    pub fn height(&self) -> f32 {
+        self.top - self.bottom
+    }
+}
 
-        // Add the right region
-        if self.right > rect.right {
-            result.push(WorldSpaceRect {
-                left: rect.right,
-                right: self.right,
-                bottom: self.bottom,
-                top: self.top,
-            });
-        }
+impl RTreeObject for WorldSpaceRect {
+    type Envelope = AABB<[f32; 2]>;
 
-        // Add the bottom region
-        if self.bottom < rect.bottom {
-            result.push(WorldSpaceRect {
-                left: rect.left,
-                right: rect.right,
-                bottom: self.bottom,
-                top: rect.bottom,
-            });
-        }
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_corners([self.left, self.bottom], [self.right, self.top])
+    }
+}
 
-        // Add the top region
-        if self.top > rect.top {
-            result.push(WorldSpaceRect {
-                left: rect.left,
-                right: rect.right,
-                bottom: rect.top,
-                top: self.top,
-            });
-        }
+/// A (category, key) pair from the layers panel, a key of `*` is every key in the category.
+pub type Layer = (String, String);
+
+/// A box on the earth along with the layers which have been loaded in it, or which need loading.
+/// These are kept in lon and lat so they stay put when world space moves.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerRegion {
+    pub rect: LonLatRect,
+    pub layers: Vec<Layer>,
+    pub boundary: Option<Arc<geo::MultiPolygon>>,   // For areas loaded by their boundary, only what is inside of it is covered
+}
+
+impl LayerRegion {
+    pub fn new(rect: LonLatRect, layers: Vec<Layer>) -> Self {
+        LayerRegion { rect, layers, boundary: None }
+    }
 
-        if result.is_empty() {
-            None
-        } else {
-            Some(result)
+    /// Whether all of `rect` has been loaded by this region.
+    pub fn covers(&self, rect: &LonLatRect) -> bool {
+        match &self.boundary {
+            Some(boundary) => boundary.contains(&geo::Rect::new(geo::Coord::from(rect.min), geo::Coord::from(rect.max))),
+            None => self.rect.contains(rect, 0.0),
         }
     }
+
+    /// Whether the layer was loaded with this region, a category loaded with `*` has all of its keys.
+    pub fn has_layer(&self, (category, key): &Layer) -> bool {
+        self.layers.iter().any(|(c, k)| c == category && (k == key || k == "*"))
+    }
 }
 
-impl RTreeObject for WorldSpaceRect {
-    type Envelope = AABB<[f32; 2]>;
+impl RTreeObject for LayerRegion {
+    type Envelope = AABB<[f64; 2]>;
 
     fn envelope(&self) -> Self::Envelope {
-        AABB::from_corners([self.left, self.bottom], [self.right, self.top])
+        self.rect.envelope()
     }
 }
 
 #[derive(Component, Clone, Debug)]
 pub struct SpatialIndex {
-    rtree: RTree<WorldSpaceRect>,
+    rtree: RTree<LayerRegion>,
 }
 
 impl SpatialIndex {
@@ -199,78 +249,239 @@ impl SpatialIndex {
         }
     }
 
-    pub fn insert(&mut self, rect: WorldSpaceRect) {
-        self.rtree.insert(rect);
+    pub fn insert(&mut self, region: LayerRegion) {
+        self.rtree.insert(region);
     }
 
-    pub fn insert_vec(&mut self, rect: Vec<WorldSpaceRect>) {
-        for rect in rect {
-            self.rtree.insert(rect);
+    pub fn insert_vec(&mut self, regions: Vec<LayerRegion>) {
+        for region in regions {
+            self.rtree.insert(region);
         }
     }
 
-    pub fn query(&self, rect: &WorldSpaceRect) -> Vec<&WorldSpaceRect> {
+    /// Forgets about a region, so the area it covered can be fetched again.
+    pub fn remove(&mut self, region: &LayerRegion) {
+        self.rtree.remove(region);
+    }
+
+    pub fn query(&self, rect: &LonLatRect) -> Vec<&LayerRegion> {
         self.rtree.locate_in_envelope_intersecting(&rect.envelope()).collect()
     }
 
-    pub fn split(&self, rect: &WorldSpaceRect) -> Vec<WorldSpaceRect> {
-        let r2: Vec<WorldSpaceRect> = self.rtree.locate_in_envelope_intersecting(&rect.envelope()).cloned().collect();
-        if !r2.is_empty() {
-            rect.split(r2).unwrap()
-        } else {
-            Vec::new()
+    /// Forgets every region with exactly this rect, whatever layers they were loaded with.
+    pub fn remove_rect(&mut self, rect: &LonLatRect) {
+        let regions: Vec<LayerRegion> = self
+            .rtree
+            .locate_in_envelope(&rect.envelope())
+            .filter(|region| region.rect == *rect)
+            .cloned()
+            .collect();
+        for region in regions {
+            self.rtree.remove(&region);
+        }
+    }
+
+    /// Every rect which has had something loaded in it.
+    pub fn regions(&self) -> Vec<LonLatRect> {
+        let mut rects: Vec<LonLatRect> = Vec::new();
+        for region in self.rtree.iter() {
+            if !rects.contains(&region.rect) {
+                rects.push(region.rect);
+            }
         }
+        rects
+    }
+
+    /// The parts of `rect` which haven't had `layer` loaded yet, this is empty if all of it has been.
+    /// Slivers left over from the edges of other rects not quite lining up are ignored.
+    /// Areas only cover what is inside their boundary, so the bits left over are dropped if one of them has all of it.
+    pub fn split(&self, rect: &LonLatRect, layer: &Layer) -> Vec<LonLatRect> {
+        let (areas, covering): (Vec<&LayerRegion>, Vec<&LayerRegion>) = self
+            .rtree
+            .locate_in_envelope_intersecting(&rect.envelope())
+            .filter(|region| region.has_layer(layer))
+            .partition(|region| region.boundary.is_some());
+        let covering: Vec<LonLatRect> = covering.into_iter().map(|region| region.rect).collect();
+        rect.split(&covering)
+            .into_iter()
+            .filter(|r| r.width() > MIN_UNCOVERED_SIZE && r.height() > MIN_UNCOVERED_SIZE)
+            .filter(|r| !areas.iter().any(|area| area.covers(r)))
+            .collect()
     }
 
-    pub fn is_covered(&self, rect: &WorldSpaceRect) -> bool {
-     //   let mut result = rect.clone();
-     //   self.rtree.locate_in_envelope_intersecting(&result.envelope()).into_iter().all(|r| {
-           //  result = result.split(r).unwrap();
-    //        true
-   //     });
+    pub fn is_covered(&self, rect: &LonLatRect, layers: &[Layer]) -> bool {
+        layers.iter().all(|layer| self.split(rect, layer).is_empty())
+    }
+
+    /// Works out what needs fetching so every one of `layers` is loaded in all of `rects`.
+    /// Layers which are missing from the same places are put together, so they can share a bounding box in the query.
+    pub fn missing(&self, rects: &[LonLatRect], layers: &[Layer]) -> Vec<LayerRegion> {
+        // What is going to be fetched counts as covered, so overlapping rects don't ask for the same place twice
+        let mut pending = self.clone();
+        let mut missing: Vec<(Vec<LonLatRect>, Vec<Layer>)> = Vec::new();
+
+        for layer in layers {
+            let mut uncovered = Vec::new();
+            for rect in rects {
+                let mut pieces = pending.split(rect, layer);
+                if pieces.len() > MAX_UNCOVERED_RECTS {
+                    // Lots of little bits make for a slow query, so just get all of them in one go
+                    pieces = LonLatRect::union(&pieces).into_iter().collect();
+                }
+                for piece in &pieces {
+                    pending.insert(LayerRegion::new(*piece, vec![layer.clone()]));
+                }
+                uncovered.append(&mut pieces);
+            }
+            if uncovered.is_empty() {
+                continue;
+            }
+            match missing.iter_mut().find(|(rects, _)| *rects == uncovered) {
+                Some((_, layers)) => layers.push(layer.clone()),
+                None => missing.push((uncovered, vec![layer.clone()])),
+            }
+        }
 
-        false   
+        missing
+            .into_iter()
+            .flat_map(|(rects, layers)| rects.into_iter().map(move |rect| LayerRegion::new(rect, layers.clone())))
+            .collect()
+    }
+}
+
+/// Where a fetched region came from, kept so it can be attributed and checked for being out of date.
+#[derive(Clone, Debug)]
+pub struct DataSource {
+    pub bounds: LonLatRect,             // The same as what is sent to overpass
+    pub source: String,                 // The url the data was requested from
+    pub generator: Option<String>,
+    pub timestamp: Option<String>,      // The time of the OSM data, not when it was downloaded
+    pub copyright: Option<String>,
+    pub fetched_at: SystemTime,
+}
+
+impl DataSource {
+    /// How old the OSM data is, this is none if the server didn't give a timestamp.
+    pub fn age(&self) -> Option<Duration> {
+        let timestamp = parse_osm_timestamp(self.timestamp.as_ref()?)?;
+        Some(SystemTime::now().duration_since(timestamp).unwrap_or_default())
+    }
+
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        // If we don't know when the data is from fall back to when we got it
+        let age = self.age().unwrap_or_else(|| self.fetched_at.elapsed().unwrap_or_default());
+        age > max_age
     }
 }
 
 #[derive(Component, Clone, Debug)]
 pub struct MapPoints {
     pub spatial_index: SpatialIndex,
-    pub refrencee_point: RefrencePoint, // Refrence point of the map, this is used to calculate the scale and offset
+    pub sources: Vec<DataSource>,       // Provenance of every region which has been loaded
 }
 
 #[derive(Resource, Clone, Debug)]
 pub struct MapBundle {
     /// A collection of map features, please put this in a spatial hashmap
+    /// Add and remove features through `insert_feature` and `remove_feature` so `feature_ids` is kept up to date
     pub features: RTree<MapFeature>,
 
+    /// Where each feature in `features` is, so duplicates can be found without searching the tree
+    feature_ids: HashMap<FeatureId, AABB<[f64; 2]>>,
+
+    /// Roughly how much memory all of the features take up
+    feature_bytes: usize,
+
+    /// Features which have been removed or replaced since the map was last respawned, their entities are out of date
+    removed: HashSet<FeatureId>,
+
+    /// Features which have been added since the map was last respawned, they may need entities
+    added: HashSet<FeatureId>,
+
     /// Map points of the map, this is used to calculate the scale and offset
     pub map_points: MapPoints,
 
     /// Global scale for rendering (used for Mercator projection)
-    pub scale: f32,
+    pub scale: f64,
 
-    pub respawn: bool,
+    pub respawn: bool,              // Spawns what has come into view and despawns what has gone, leaving everything else
+    pub redraw: bool,               // Respawns every entity, such as when the projection has changed
+    pub restyle: Vec<Layer>,        // Layers whose colors or widths have changed, their entities are restyled in place
     pub get_more_data: bool,
+    pub refresh: bool,              // Asks overpass for what has changed in every loaded region
+    pub clear: bool,                // Unloads everything, the entities and the data
 }
 
 
 impl MapBundle {
-    pub fn new(long: f32, lat: f32, scale: f32) -> Self {
+    pub fn new(scale: f64) -> Self {
         Self {
             features: RTree::new(),
+            feature_ids: HashMap::new(),
+            feature_bytes: 0,
+            removed: HashSet::new(),
+            added: HashSet::new(),
             map_points: MapPoints {
-                refrencee_point: RefrencePoint::new(long, lat),
                 spatial_index: SpatialIndex::new(),
+                sources: Vec::new(),
             },
             scale,
             respawn: false,
+            redraw: false,
+            restyle: Vec::new(),
             get_more_data: false,
+            refresh: false,
+            clear: false,
         }
     }
 
-    // Method to apply a Mercator projection to a coordinate, otherwise the coordinates will be too small to be rendered
-    pub fn lat_lon_to_mercator(&self, lat: f32, lon: f32) -> Vec2 {
-        lat_lon_to_world_mercator(lat, lon, self.scale, self.map_points.refrencee_point.long, self.map_points.refrencee_point.lat)
+    /// Adds a feature, if one with the same id is already loaded it is left as it is and false is returned.
+    pub fn insert_feature(&mut self, feature: MapFeature) -> bool {
+        if self.feature_ids.contains_key(&feature.id) {
+            return false;
+        }
+        self.feature_ids.insert(feature.id, feature.envelope());
+        self.feature_bytes += feature.estimated_size();
+        self.added.insert(feature.id);
+        self.features.insert(feature);
+        true
+    }
+
+    pub fn get_feature(&self, id: FeatureId) -> Option<&MapFeature> {
+        let envelope = *self.feature_ids.get(&id)?;
+        self.features.locate_with_selection_function(FeatureIdSelection { id, envelope }).next()
+    }
+
+    pub fn remove_feature(&mut self, id: FeatureId) -> Option<MapFeature> {
+        let envelope = self.feature_ids.remove(&id)?;
+        let feature = self.features.remove_with_selection_function(FeatureIdSelection { id, envelope })?;
+        self.feature_bytes = self.feature_bytes.saturating_sub(feature.estimated_size());
+        self.removed.insert(id);
+        Some(feature)
+    }
+
+    /// The features removed since this was last called, so their entities can be despawned.
+    pub fn take_removed(&mut self) -> HashSet<FeatureId> {
+        std::mem::take(&mut self.removed)
+    }
+
+    /// The features added since this was last called, so entities can be spawned for just them.
+    pub fn take_added(&mut self) -> HashSet<FeatureId> {
+        std::mem::take(&mut self.added)
+    }
+
+    pub fn feature_bytes(&self) -> usize {
+        self.feature_bytes
+    }
+
+    /// Throws away every feature along with what has been loaded and where it came from, so it is all fetched again.
+    pub fn unload_all(&mut self) {
+        self.features = RTree::new();
+        self.feature_ids.clear();
+        self.feature_bytes = 0;
+        self.removed.clear();
+        self.added.clear();
+        self.map_points.spatial_index = SpatialIndex::new();
+        self.map_points.sources.clear();
     }
 }
\ No newline at end of file
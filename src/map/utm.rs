@@ -0,0 +1,110 @@
+use bevy::math::DVec2;
+
+use super::{LonLat, Projection};
+
+// WGS84, which is what OSM coordinates are in
+const A: f64 = 6_378_137.0;
+const F: f64 = 1.0 / 298.257_223_563;
+const K0: f64 = 0.9996;
+const FALSE_EASTING: f64 = 500_000.0;
+const FALSE_NORTHING: f64 = 10_000_000.0;   // Only used south of the equator
+
+/// Universal Transverse Mercator, good to a part in a few thousand anywhere in its zone.
+/// The zone is fixed once it has been picked so moving across a zone edge doesn't change the shape of the map.
+/// This uses the series from Snyder's Map Projections - A Working Manual, which are good to about a millimetre within a zone.
+pub struct Utm {
+    pub zone: u8,
+    pub north: bool,
+}
+
+impl Utm {
+    /// The zone a point is in, ignoring the exceptions around Norway and Svalbard.
+    pub fn for_point(point: LonLat) -> Self {
+        let zone = (((point.lon + 180.0) / 6.0).floor() as i32).rem_euclid(60) + 1;
+        Utm { zone: zone as u8, north: point.lat >= 0.0 }
+    }
+
+    fn central_meridian(&self) -> f64 {
+        (self.zone as f64 - 1.0) * 6.0 - 180.0 + 3.0
+    }
+
+    fn false_northing(&self) -> f64 {
+        if self.north { 0.0 } else { FALSE_NORTHING }
+    }
+}
+
+fn e2() -> f64 {
+    F * (2.0 - F)
+}
+
+/// The distance along the meridian from the equator to `lat`, in metres.
+fn meridian_arc(lat: f64) -> f64 {
+    let e2 = e2();
+    let (e4, e6) = (e2 * e2, e2 * e2 * e2);
+    A * ((1.0 - e2 / 4.0 - 3.0 * e4 / 64.0 - 5.0 * e6 / 256.0) * lat
+        - (3.0 * e2 / 8.0 + 3.0 * e4 / 32.0 + 45.0 * e6 / 1024.0) * (2.0 * lat).sin()
+        + (15.0 * e4 / 256.0 + 45.0 * e6 / 1024.0) * (4.0 * lat).sin()
+        - (35.0 * e6 / 3072.0) * (6.0 * lat).sin())
+}
+
+impl Projection for Utm {
+    fn name(&self) -> String {
+        format!("UTM zone {}{}", self.zone, if self.north { "N" } else { "S" })
+    }
+
+    fn forward(&self, point: LonLat) -> DVec2 {
+        let e2 = e2();
+        let ep2 = e2 / (1.0 - e2);
+        let lat = point.lat.to_radians();
+
+        let n = A / (1.0 - e2 * lat.sin().powi(2)).sqrt();
+        let t = lat.tan().powi(2);
+        let c = ep2 * lat.cos().powi(2);
+        let a = lat.cos() * (point.lon - self.central_meridian()).to_radians();
+
+        let x = K0 * n * (a + (1.0 - t + c) * a.powi(3) / 6.0 + (5.0 - 18.0 * t + t * t + 72.0 * c - 58.0 * ep2) * a.powi(5) / 120.0);
+        let y = K0 * (meridian_arc(lat) + n * lat.tan() * (a * a / 2.0
+            + (5.0 - t + 9.0 * c + 4.0 * c * c) * a.powi(4) / 24.0
+            + (61.0 - 58.0 * t + t * t + 600.0 * c - 330.0 * ep2) * a.powi(6) / 720.0));
+
+        DVec2::new(x + FALSE_EASTING, y + self.false_northing())
+    }
+
+    fn inverse(&self, point: DVec2) -> LonLat {
+        let e2 = e2();
+        let ep2 = e2 / (1.0 - e2);
+        let e1 = (1.0 - (1.0 - e2).sqrt()) / (1.0 + (1.0 - e2).sqrt());
+
+        // Work out the latitude of the foot of the meridian first, then correct it for how far east the point is
+        let m = (point.y - self.false_northing()) / K0;
+        let mu = m / (A * (1.0 - e2 / 4.0 - 3.0 * e2 * e2 / 64.0 - 5.0 * e2.powi(3) / 256.0));
+        let foot = mu
+            + (3.0 * e1 / 2.0 - 27.0 * e1.powi(3) / 32.0) * (2.0 * mu).sin()
+            + (21.0 * e1 * e1 / 16.0 - 55.0 * e1.powi(4) / 32.0) * (4.0 * mu).sin()
+            + (151.0 * e1.powi(3) / 96.0) * (6.0 * mu).sin()
+            + (1097.0 * e1.powi(4) / 512.0) * (8.0 * mu).sin();
+
+        let c = ep2 * foot.cos().powi(2);
+        let t = foot.tan().powi(2);
+        let n = A / (1.0 - e2 * foot.sin().powi(2)).sqrt();
+        let r = A * (1.0 - e2) / (1.0 - e2 * foot.sin().powi(2)).powf(1.5);
+        let d = (point.x - FALSE_EASTING) / (n * K0);
+
+        let lat = foot - (n * foot.tan() / r) * (d * d / 2.0
+            - (5.0 + 3.0 * t + 10.0 * c - 4.0 * c * c - 9.0 * ep2) * d.powi(4) / 24.0
+            + (61.0 + 90.0 * t + 298.0 * c + 45.0 * t * t - 252.0 * ep2 - 3.0 * c * c) * d.powi(6) / 720.0);
+        let lon = (d - (1.0 + 2.0 * t + c) * d.powi(3) / 6.0
+            + (5.0 - 2.0 * c + 28.0 * t - 3.0 * c * c + 8.0 * ep2 + 24.0 * t * t) * d.powi(5) / 120.0) / foot.cos();
+
+        LonLat::new(self.central_meridian() + lon.to_degrees(), lat.to_degrees())
+    }
+
+    fn scale_factor(&self, point: LonLat) -> f64 {
+        let ep2 = e2() / (1.0 - e2());
+        let lat = point.lat.to_radians();
+        let t = lat.tan().powi(2);
+        let c = ep2 * lat.cos().powi(2);
+        let a = lat.cos() * (point.lon - self.central_meridian()).to_radians();
+        K0 * (1.0 + (1.0 + c) * a * a / 2.0 + (5.0 - 4.0 * t + 42.0 * c + 13.0 * c * c - 28.0 * ep2) * a.powi(4) / 24.0)
+    }
+}
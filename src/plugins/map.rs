@@ -2,21 +2,50 @@ use bevy::{
     diagnostic::FrameTimeDiagnosticsPlugin,
     prelude::*,
 };
-use crate::{map::{MapBundle, SCALE, STARTING_LONG_LAT}, systems::*};
+use crate::{map::{MapBundle, WorldSpace, SCALE}, systems::*};
 
 pub struct MapPlugin;
 
 impl Plugin for MapPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(MapBundle::new(STARTING_LONG_LAT.x, STARTING_LONG_LAT.y, SCALE))
-            .add_systems(Startup, spawn_starting_point)
+        let (map_sender, map_receiver) = map_channel();
+        app.insert_resource(MapBundle::new(SCALE))
+            .add_systems(Startup, (choose_start_location, spawn_starting_point).chain())
+            .add_systems(Update, move_to_start_location)
+            .add_systems(Last, save_session)
+            .add_systems(First, (rebase_world_origin, reproject_map).chain())
             .add_systems(Update, check_map_info)
             .add_systems(Update, (handle_mouse, handle_keyboard))
             .add_systems(Update, camera_change)
-            .add_systems(Update, (bbox_system, respawn_map))
+            .add_systems(Update, reload_map_style.before(respawn_map))
+            .add_systems(Update, (bbox_system, respawn_map, restyle_map.after(respawn_map), spawn_tessellated_shapes.after(restyle_map)))
             .add_systems(FixedUpdate, read_map_receiver)
+            .add_systems(Update, (provenance_panel, draw_stale_regions, draw_attribution, draw_scale_bar, draw_labels))
+            .add_systems(Update, (refresh_map_data, draw_change_highlights))
+            .add_systems(Update, (history_panel, fetch_snapshot, respawn_snapshot.before(respawn_map), update_compare_visibility.after(respawn_map)))
+            .add_systems(Update, (place_search_panel, fly_camera, whats_here))
+            .add_systems(Update, (area_loader_panel, draw_area_boundaries))
+            .add_systems(Update, activity_panel)
+            .add_systems(Update, (enforce_memory_budget.before(respawn_map), clear_map.before(bbox_system)))
             .insert_resource(PersistentInfoWindows::default())
-            .insert_resource(MapBundle::new(STARTING_LONG_LAT.x, STARTING_LONG_LAT.y, SCALE))
+            .insert_resource(map_sender)
+            .insert_resource(map_receiver)
+            .init_resource::<ProvenanceSettings>()
+            .init_resource::<ChangeHighlights>()
+            .init_resource::<HistoricalView>()
+            .init_resource::<GeocoderSettings>()
+            .init_resource::<PlaceSearch>()
+            .init_resource::<WhatsHere>()
+            .init_resource::<AreaLoader>()
+            .init_resource::<ActiveRequests>()
+            .init_resource::<MemoryBudget>()
+            .init_resource::<StartLocation>()
+            .init_resource::<ProjectionSettings>()
+            .init_resource::<WorldSpace>()
+            .init_resource::<BatchedRendering>()
+            .init_resource::<ShapeTessellator>()
+            .init_resource::<MapStyle>()
+            .insert_resource(MapBundle::new(SCALE))
             .add_plugins(SettingsPlugin);
         if cfg!(debug_assertions) {
             app.add_plugins(FrameTimeDiagnosticsPlugin)
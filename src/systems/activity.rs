@@ -0,0 +1,142 @@
+use bevy::prelude::*;
+use bevy_egui::{egui::{self, Color32, RichText}, EguiContexts};
+
+use crate::{map::{LayerRegion, MapBundle}, webapi::{RequestId, RequestPhase, RequestProgress}};
+
+use super::HistoricalView;
+
+/// Every request to overpass which hasn't finished yet, these are listed in the activity widget.
+#[derive(Resource, Default)]
+pub struct ActiveRequests {
+    requests: Vec<TrackedRequest>,
+}
+
+struct TrackedRequest {
+    progress: RequestProgress,
+    covers: Vec<LayerRegion>,           // The regions marked as loaded for the request, freed up again if it doesn't finish
+    historical: bool,                   // The regions are in the snapshot's spatial index rather than the map's
+    done: bool,                         // All of it has been read off the channel
+}
+
+impl TrackedRequest {
+    /// Frees up what the request had marked as loaded, so it can be fetched again.
+    fn release(&mut self, map_bundle: &mut MapBundle, history: &mut HistoricalView) {
+        let spatial_index = if self.historical { &mut history.spatial_index } else { &mut map_bundle.map_points.spatial_index };
+        for covers in self.covers.drain(..) {
+            spatial_index.remove(&covers);
+        }
+    }
+}
+
+impl ActiveRequests {
+    /// Starts keeping track of a request, the returned progress should be handed to the thread doing it.
+    pub fn track(&mut self, kind: &str, area: String, covers: Vec<LayerRegion>) -> RequestProgress {
+        let progress = RequestProgress::new(kind, area);
+        self.requests.push(TrackedRequest { progress: progress.clone(), covers, historical: false, done: false });
+        progress
+    }
+
+    /// The same as `track`, for a request whose regions are in the snapshot's spatial index.
+    pub fn track_snapshot(&mut self, kind: &str, area: String, covers: Vec<LayerRegion>) -> RequestProgress {
+        let progress = RequestProgress::new(kind, area);
+        self.requests.push(TrackedRequest { progress: progress.clone(), covers, historical: true, done: false });
+        progress
+    }
+
+    /// Whether a request is still being tracked and hasn't been cancelled.
+    pub fn is_running(&self, id: RequestId) -> bool {
+        self.requests.iter().any(|r| r.progress.id() == id && !r.progress.is_cancelled())
+    }
+
+    /// Adds to the regions a request covers, for requests which only find out what they cover once they have started.
+    pub fn add_covers(&mut self, id: RequestId, covers: Vec<LayerRegion>) {
+        if let Some(request) = self.requests.iter_mut().find(|r| r.progress.id() == id) {
+            request.covers.extend(covers);
+        }
+    }
+
+    /// Called once everything a request sent has been added to the map.
+    pub fn finish(&mut self, id: RequestId) {
+        if let Some(request) = self.requests.iter_mut().find(|r| r.progress.id() == id) {
+            request.done = true;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Cancels every request and stops tracking them, for when everything they would load is being thrown away.
+    pub fn cancel_all(&mut self) {
+        for request in self.requests.drain(..) {
+            request.progress.cancel();
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1024. * 1024.))
+    }
+}
+
+/// Shows what is being downloaded in the bottom right, each request can be cancelled from here.
+pub fn activity_panel(
+    mut contexts: EguiContexts,
+    mut active_requests: ResMut<ActiveRequests>,
+    mut map_bundle: ResMut<MapBundle>,
+    mut history: ResMut<HistoricalView>,
+) {
+    // Finished requests hang around until the map has been respawned with their features
+    let respawned = !map_bundle.respawn;
+    active_requests.requests.retain(|r| !(r.done && respawned));
+
+    // Anything which didn't make it shouldn't stop the area from being fetched again
+    for request in &mut active_requests.requests {
+        if request.progress.is_cancelled() || request.progress.phase() == RequestPhase::Failed {
+            request.release(&mut map_bundle, &mut history);
+        }
+    }
+
+    if active_requests.is_empty() {
+        return;
+    }
+
+    let mut remove = None;
+    egui::Window::new("Activity")
+        .anchor(egui::Align2::RIGHT_BOTTOM, [-8.0, -8.0])
+        .resizable(false)
+        .collapsible(true)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Grid::new("activity_grid").striped(true).show(ui, |ui| {
+                for (i, request) in active_requests.requests.iter().enumerate() {
+                    let progress = &request.progress;
+                    let phase = progress.phase();
+                    ui.vertical(|ui| {
+                        ui.label(RichText::new(progress.kind()).strong());
+                        ui.label(RichText::new(progress.area()).small());
+                    });
+                    let phase_text = RichText::new(phase.name());
+                    ui.label(if phase == RequestPhase::Failed { phase_text.color(Color32::from_rgb(230, 90, 90)) } else { phase_text });
+                    ui.label(format!("{:.1}s", progress.elapsed().as_secs_f32()));
+                    ui.label(format_bytes(progress.bytes()));
+                    if phase == RequestPhase::Failed {
+                        if ui.small_button("Dismiss").clicked() {
+                            remove = Some(i);
+                        }
+                    } else if phase != RequestPhase::Spawning && ui.small_button("Cancel").clicked() {
+                        progress.cancel();
+                        remove = Some(i);
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+    if let Some(i) = remove {
+        active_requests.requests.remove(i).release(&mut map_bundle, &mut history);
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_egui::{egui::{self, Color32, RichText}, EguiContexts};
+
+use crate::{map::{AreaBoundary, AreaRef, Layer, LayerRegion, LonLatRect, MapBundle, WorldSpace}, webapi::get_overpass_area};
+
+use super::{ActiveRequests, FlyTo, MapSender, SettingsOverlay};
+
+/// The "Load area" dialog and the boundaries of the areas which have been loaded.
+#[derive(Resource, Default)]
+pub struct AreaLoader {
+    pub open: bool,
+    pub input: String,
+    pub status: Option<String>,
+    pub areas: Vec<(AreaBoundary, Vec<Vec<Vec2>>)>, // The boundaries along with their rings in world space, worked out again when it moves
+    fly_to: Option<LonLatRect>,
+}
+
+impl AreaLoader {
+    /// Takes in the boundary of an area that is being loaded, marking everything inside of it as covered for the layers it is loaded with.
+    /// Gives back what was marked, so it can be freed up again if the request doesn't finish.
+    pub fn add_area(&mut self, map_bundle: &mut MapBundle, world_space: &WorldSpace, boundary: AreaBoundary, layers: Vec<Layer>) -> Vec<LayerRegion> {
+        if boundary.rings.is_empty() {
+            self.status = Some(format!("Couldn't find {}", boundary.name));
+            return Vec::new();
+        }
+        self.status = Some(format!("Loading {}...", boundary.name));
+        let Some(bounds) = boundary.bounds() else { return Vec::new() };
+        // One region for the whole area, what is in it is worked out from the boundary
+        let covers = LayerRegion { rect: bounds, layers, boundary: Some(Arc::new(boundary.polygons())) };
+        map_bundle.map_points.spatial_index.insert(covers.clone());
+
+        self.fly_to = Some(bounds);
+        let rings = boundary.rings_in_world_space(world_space);
+        self.areas.push((boundary, rings));
+        vec![covers]
+    }
+
+    /// Forgets the areas which have been loaded, the dialog is left as it is.
+    pub fn clear(&mut self) {
+        self.areas.clear();
+        self.status = None;
+        self.fly_to = None;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn area_loader_panel(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut area_loader: ResMut<AreaLoader>,
+    overpass_settings: Res<SettingsOverlay>,
+    map_sender: Res<MapSender>,
+    camera: Query<Entity, With<Camera2d>>,
+    primary_window_query: Query<&Window, With<PrimaryWindow>>,
+    mut active_requests: ResMut<ActiveRequests>,
+    world_space: Res<WorldSpace>,
+) {
+    if let Some(rect) = area_loader.fly_to.take() {
+        commands.entity(camera.single()).insert(FlyTo::to_rect(&world_space.rect_to_world(rect), primary_window_query.single(), &world_space));
+    }
+
+    let mut open = area_loader.open;
+    egui::Window::new("Load area")
+        .open(&mut open)
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label("Name or OSM relation id");
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut area_loader.input);
+                if ui.button("Load").clicked() {
+                    match AreaRef::parse(&area_loader.input) {
+                        Some(area) => {
+                            area_loader.status = Some("Finding the area...".to_string());
+                            let progress = active_requests.track("Area", area_loader.input.trim().to_string(), Vec::new());
+                            let overpass_settings_clone = overpass_settings.clone();
+                            let tx = (*map_sender).clone();
+                            std::thread::spawn(move || {
+                                get_overpass_area(area, &overpass_settings_clone, &progress, &tx);
+                            });
+                        }
+                        None => area_loader.status = Some("Type in a name or a relation id".to_string()),
+                    }
+                }
+            });
+            if let Some(status) = &area_loader.status {
+                ui.label(RichText::new(status).color(Color32::from_rgb(180, 180, 180)));
+            }
+            if !area_loader.areas.is_empty() {
+                ui.separator();
+                let mut remove = None;
+                for (i, (boundary, _)) in area_loader.areas.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(&boundary.name);
+                        if ui.small_button("Hide").clicked() {
+                            remove = Some(i);
+                        }
+                    });
+                }
+                if let Some(i) = remove {
+                    area_loader.areas.remove(i);
+                }
+            }
+        });
+    area_loader.open = open;
+}
+
+/// Outlines the boundaries of the loaded areas.
+pub fn draw_area_boundaries(
+    mut gizmos: Gizmos,
+    mut area_loader: ResMut<AreaLoader>,
+    world_space: Res<WorldSpace>,
+) {
+    // The rings are only kept in world space for drawing, so they are worked out again whenever it moves
+    if world_space.is_changed() {
+        for (boundary, rings) in &mut area_loader.areas {
+            *rings = boundary.rings_in_world_space(&world_space);
+        }
+    }
+    for (_, rings) in &area_loader.areas {
+        for ring in rings {
+            gizmos.linestrip_2d(ring.iter().copied(), Srgba::new(0.35, 0.65, 0.95, 1.0));
+        }
+    }
+}
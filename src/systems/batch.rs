@@ -0,0 +1,104 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use bevy::prelude::*;
+use rstar::{Envelope, RTreeObject, AABB};
+
+use crate::map::{lat_lon_to_tile_mercator, tile_to_lat_lon, FeatureId, Layer, MapBundle, MapFeature, WorldSpace};
+
+use super::{feature_layer, shape_entity, FeatureStyle, ShapeJob, ShapeTessellator};
+
+/// The slippy map zoom level features are batched at, so a tile is a couple of kilometres across.
+pub const BATCH_TILE_ZOOM: i32 = 14;
+
+pub type TileKey = (i32, i32);
+
+/// Whether features are drawn merged into a mesh per tile and layer rather than an entity each.
+/// Each feature is still in the feature tree, which is what clicking on them goes through.
+#[derive(Resource, Default)]
+pub struct BatchedRendering {
+    pub enabled: bool,
+}
+
+/// A mesh with every feature of one layer in one tile.
+#[derive(Component)]
+pub struct BatchedTile {
+    pub tile: TileKey,
+    pub layer: Layer,
+    pub features: Arc<HashSet<FeatureId>>,  // What is in the batch the mesh is part of, so it can be told if it is out of date. Shared by every entity in the batch
+}
+
+impl BatchedTile {
+    /// Whether the mesh has exactly these features in it.
+    pub fn has_features(&self, features: &[&MapFeature]) -> bool {
+        self.features.len() == features.len() && features.iter().all(|feature| self.features.contains(&feature.id))
+    }
+}
+
+/// The tile a feature is batched into, which goes by the middle of it so it is only ever in one.
+pub fn feature_tile(feature: &MapFeature) -> TileKey {
+    let center = feature.envelope().center();
+    lat_lon_to_tile_mercator(center[1], center[0], BATCH_TILE_ZOOM)
+}
+
+/// The features in an enabled layer, grouped by tile and layer, for every tile which has something in `viewport`.
+/// Whole tiles are included even if only part of them is in view, so they don't need building again as the camera moves.
+pub fn batch_groups<'a>(
+    map_bundle: &'a MapBundle,
+    enabled: &'a [Layer],
+    viewport: &AABB<[f64; 2]>,
+) -> HashMap<(TileKey, Layer), Vec<&'a MapFeature>> {
+    let tiles: HashSet<TileKey> = map_bundle.features
+        .locate_in_envelope_intersecting(viewport)
+        .map(feature_tile)
+        .collect();
+
+    let mut groups: HashMap<(TileKey, Layer), Vec<&MapFeature>> = HashMap::new();
+    for tile in tiles {
+        let (north, west) = tile_to_lat_lon(tile.0, tile.1, BATCH_TILE_ZOOM);
+        let (south, east) = tile_to_lat_lon(tile.0 + 1, tile.1 + 1, BATCH_TILE_ZOOM);
+        let bounds = AABB::from_corners([west, south], [east, north]);
+        for feature in map_bundle.features.locate_in_envelope_intersecting(&bounds) {
+            if feature_tile(feature) != tile {
+                continue;
+            }
+            if let Some(layer) = feature_layer(feature, enabled) {
+                groups.entry((tile, layer.clone())).or_default().push(feature);
+            }
+        }
+    }
+    groups
+}
+
+/// Spawns the entities for a batch, one for each elevation in it so casings and bridges still go above or below the rest,
+/// along with one for each label.
+/// Their meshes are tessellated in the background and turn up a few frames later.
+pub fn spawn_batch(
+    commands: &mut Commands,
+    tessellator: &mut ShapeTessellator,
+    world_space: &WorldSpace,
+    (tile, layer): (TileKey, Layer),
+    features: Vec<(MapFeature, FeatureStyle)>,
+) {
+    let ids: Arc<HashSet<FeatureId>> = Arc::new(features.iter().map(|(feature, _)| feature.id).collect());
+    let mut by_elevation: HashMap<u32, Vec<(MapFeature, FeatureStyle)>> = HashMap::new();
+    for (feature, style) in features {
+        if let Some(label) = style.label.clone() {
+            // Labels are drawn on their own, so they get an entity each which goes when the batch does
+            commands.spawn((label, Visibility::default(), BatchedTile { tile, layer: layer.clone(), features: ids.clone() }));
+        }
+        if let Some(casing) = style.casing_style() {
+            by_elevation.entry(casing.elevation.to_bits()).or_default().push((feature.clone(), casing));
+        }
+        by_elevation.entry(style.elevation.to_bits()).or_default().push((feature, style));
+    }
+
+    let mut jobs = Vec::new();
+    for (elevation, features) in by_elevation {
+        let entity = commands.spawn((
+            shape_entity(tessellator, f32::from_bits(elevation)),
+            BatchedTile { tile, layer: layer.clone(), features: ids.clone() },
+        )).id();
+        jobs.push(ShapeJob { entity, features });
+    }
+    tessellator.submit(jobs, world_space);
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use bevy::{prelude::*, window::PrimaryWindow};
+use rstar::RTreeObject;
+
+use crate::map::{FeatureId, LonLatRect, MapBundle, MapFeature, WorldSpace};
+
+use super::{camera_space_to_world_space, ActiveRequests, AreaLoader, BatchedTile, HistoricalFeature, HistoricalView, MapReceiver, RegionBackground};
+
+/// How much can be loaded before the regions which haven't been looked at for the longest are unloaded.
+#[derive(Resource)]
+pub struct MemoryBudget {
+    pub max_features: usize,
+    pub max_megabytes: f32,
+    region_views: HashMap<LonLatRect, f32>, // When each loaded region was last in view, in seconds since the start
+    viewport: Option<LonLatRect>,           // What was in view when `region_views` was last brought up to date
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        MemoryBudget {
+            max_features: 250_000,
+            max_megabytes: 512.0,
+            region_views: HashMap::new(),
+            viewport: None,
+        }
+    }
+}
+
+impl MemoryBudget {
+    fn is_over(&self, map_bundle: &MapBundle) -> bool {
+        map_bundle.features.size() > self.max_features
+            || map_bundle.feature_bytes() as f32 > self.max_megabytes * 1024.0 * 1024.0
+    }
+
+    fn last_viewed(&self, rect: &LonLatRect) -> f32 {
+        // Regions which were never looked at, such as the edges of a loaded area, go first
+        self.region_views.get(rect).copied().unwrap_or(0.0)
+    }
+
+}
+
+/// Unloads a region, along with every feature which isn't also in a region that is still loaded.
+fn evict_region(commands: &mut Commands, map_bundle: &mut MapBundle, backgrounds: &Query<(Entity, &RegionBackground)>, rect: &LonLatRect) {
+    map_bundle.map_points.spatial_index.remove_rect(rect);
+
+    let unloaded: Vec<FeatureId> = map_bundle
+        .features
+        .locate_in_envelope_intersecting(&rect.envelope())
+        .filter(|feature| map_bundle.map_points.spatial_index.query(&LonLatRect::from_envelope(&feature.envelope())).is_empty())
+        .map(|feature| feature.id)
+        .collect();
+    for id in unloaded {
+        map_bundle.remove_feature(id);
+    }
+    map_bundle.map_points.sources.retain(|source| !rect.contains(&source.bounds, 0.0));
+
+    for (entity, background) in backgrounds.iter() {
+        if background.0 == *rect {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Keeps track of which regions are in view, and unloads the ones which were looked at longest ago while over budget.
+#[allow(clippy::too_many_arguments)]
+pub fn enforce_memory_budget(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut budget: ResMut<MemoryBudget>,
+    mut map_bundle: ResMut<MapBundle>,
+    camera_query: Query<&GlobalTransform, With<Camera2d>>,
+    primary_window_query: Query<&Window, With<PrimaryWindow>>,
+    ortho_projection_query: Query<&OrthographicProjection, With<Camera>>,
+    backgrounds: Query<(Entity, &RegionBackground)>,
+    world_space: Res<WorldSpace>,
+) {
+    let Some(viewport) = camera_space_to_world_space(camera_query.single(), primary_window_query.single(), ortho_projection_query.single().clone(), 1.25) else {
+        return;
+    };
+    let viewport = world_space.rect_to_lon_lat(&viewport);
+
+    // Only looked at when the camera moves. What was in view has been until now, and anything loaded
+    // in view since then can't be unloaded until the camera moves away from it anyway
+    if budget.viewport != Some(viewport) {
+        let now = time.elapsed_secs();
+        let previous = budget.viewport.replace(viewport);
+        for rect in previous.iter().chain([&viewport]) {
+            for region in map_bundle.map_points.spatial_index.query(rect) {
+                budget.region_views.insert(region.rect, now);
+            }
+        }
+    }
+
+    if !budget.is_over(&map_bundle) {
+        return;
+    }
+
+    // Anything in view stays, however much is loaded
+    let mut candidates: Vec<LonLatRect> = map_bundle
+        .map_points
+        .spatial_index
+        .regions()
+        .into_iter()
+        .filter(|rect| !rect.intersects(&viewport))
+        .collect();
+    candidates.sort_by(|a, b| budget.last_viewed(a).total_cmp(&budget.last_viewed(b)));
+
+    let mut evicted = 0;
+    for rect in candidates {
+        if !budget.is_over(&map_bundle) {
+            break;
+        }
+        evict_region(&mut commands, &mut map_bundle, &backgrounds, &rect);
+        budget.region_views.remove(&rect);
+        evicted += 1;
+    }
+    if evicted > 0 {
+        info!("Unloaded {} regions, {} features left", evicted, map_bundle.features.size());
+        map_bundle.respawn = true;
+    }
+}
+
+/// Clears the map when "Clear Map" is pressed, the data and what has been loaded go as well as the entities.
+/// Requests which are still going are cancelled, along with the loaded areas and the historical snapshot.
+#[allow(clippy::too_many_arguments)]
+pub fn clear_map(
+    mut commands: Commands,
+    mut map_bundle: ResMut<MapBundle>,
+    mut budget: ResMut<MemoryBudget>,
+    mut active_requests: ResMut<ActiveRequests>,
+    mut area_loader: ResMut<AreaLoader>,
+    mut history: ResMut<HistoricalView>,
+    map_receiver: Res<MapReceiver>,
+    shapes_query: Query<Entity, (With<MapFeature>, Without<HistoricalFeature>)>,
+    backgrounds: Query<Entity, With<RegionBackground>>,
+    batches: Query<Entity, With<BatchedTile>>,
+) {
+    if !map_bundle.clear {
+        return;
+    }
+    map_bundle.clear = false;
+
+    for entity in shapes_query.iter().chain(backgrounds.iter()).chain(batches.iter()) {
+        commands.entity(entity).despawn_recursive();
+    }
+    active_requests.cancel_all();
+    // Whatever they had already sent would put some of the map back
+    for _ in map_receiver.try_iter() {}
+    map_bundle.unload_all();
+    budget.region_views.clear();
+    budget.viewport = None;
+    area_loader.clear();
+    history.clear();
+    info!("Cleared the map");
+}
@@ -1,18 +1,24 @@
 use bevy::{core_pipeline::bloom::Bloom, prelude::*};
 use bevy_pancam::{DirectionKeys, PanCam};
 
-use crate::map::{MapBundle, WorldSpaceRect};
+use crate::map::{camera_scale_for_zoom, detail_level, zoom_level, MapBundle, WorldSpace, WorldSpaceRect, MAX_ZOOM};
 
-use super::{orientation::CameraRotation, SettingsOverlay};
+use super::{orientation::CameraRotation, zoom_sized_layers, MapStyle, SettingsOverlay, ShapeTessellator};
 
 
 
+/// Buildings are hidden when zoomed out further than this, there are too many of them to draw.
+const BUILDING_MIN_ZOOM: f64 = 16.0;
+/// How far the camera zooms before the narrowest roads are made wider or thinner to stay the same number of pixels across.
+const WAY_WIDTH_ZOOM_STEP: f64 = 0.5;
+
 #[derive(Resource)]
 pub struct CameraSettings {
     pub scale: f32,
+    pub zoom: f64,      // The slippy map zoom level for `scale`
 }
 
-pub fn setup_camera(mut commands: Commands) {
+pub fn setup_camera(mut commands: Commands, world_space: Res<WorldSpace>) {
     commands.spawn((
         Camera2d,
         Camera {
@@ -30,7 +36,7 @@ pub fn setup_camera(mut commands: Commands) {
             speed: 400., // the speed for the keyboard movement
             enabled: true, // when false, controls are disabled. See toggle example.
             zoom_to_cursor: true, // whether to zoom towards the mouse or the center of the screen
-            min_scale: 0.25, // prevent the camera from zooming too far in
+            min_scale: camera_scale_for_zoom(MAX_ZOOM, world_space.origin, &world_space), // prevent the camera from zooming too far in
             max_scale: f32::INFINITY, // prevent the camera from zooming too far out
             min_x: f32::NEG_INFINITY, // minimum x position of the camera window
             max_x: f32::INFINITY, // maximum x position of the camera window
@@ -42,18 +48,44 @@ pub fn setup_camera(mut commands: Commands) {
     ));
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn camera_change(
     mut camera_settings: ResMut<CameraSettings>,
     mut query: Query<&mut OrthographicProjection, With<Camera>>,
+    camera: Query<&Transform, With<Camera2d>>,
     mut overpass_settings: ResMut<SettingsOverlay>,
     mut map_bundle: ResMut<MapBundle>,
+    mut tessellator: ResMut<ShapeTessellator>,
+    map_style: Res<MapStyle>,
+    world_space: Res<WorldSpace>,
 ) {
     // TODO: Need to work on zoning what to spawn in and not to based of camera view.
     // TODO: get the data before it needs to show!
     let projection = query.single_mut();
     if projection.is_changed() {
         camera_settings.scale = projection.scale;
-        if camera_settings.scale > 3.5 {
+        let center = world_space.to_lon_lat(camera.single().translation.truncate().into());
+        camera_settings.zoom = zoom_level(projection.scale, center, &world_space);
+        // Everything is drawn again at the new level of detail, each level is cached so going back is cheaper
+        let detail = detail_level(camera_settings.zoom);
+        if detail != tessellator.detail {
+            tessellator.detail = detail;
+            map_bundle.redraw = true;
+        }
+        // Roads have a minimum width in pixels, only the layers which can be that thin are restyled as it changes
+        let way_zoom = (camera_settings.zoom / WAY_WIDTH_ZOOM_STEP).round() * WAY_WIDTH_ZOOM_STEP;
+        if way_zoom != tessellator.zoom {
+            tessellator.zoom = way_zoom;
+            if !map_bundle.redraw {
+                let enabled = overpass_settings.get_true_keys_with_category_with_individual();
+                for layer in zoom_sized_layers(&enabled, &map_style.stylesheet, tessellator.detail) {
+                    if !map_bundle.restyle.contains(&layer) {
+                        map_bundle.restyle.push(layer);
+                    }
+                }
+            }
+        }
+        if camera_settings.zoom < BUILDING_MIN_ZOOM {
             if let Some(category) = overpass_settings.categories.get_mut("Building") {
                 if !category.disabled {
                     category.disabled = true;
@@ -69,7 +101,7 @@ pub fn camera_change(
             } 
         }
         /*
-        if camera_settings.scale > 10.0 {
+        if camera_settings.zoom < 14.5 {
             if let Some(category) = overpass_settings.categories.get_mut("Highway") {
                 category.set_children(false);
                 if let Some((item, _)) = category.items.get_mut("motorway") {
@@ -92,6 +124,58 @@ pub fn camera_change(
     }
 }
 
+/// How long flying the camera to somewhere takes, in seconds.
+const FLY_TIME: f32 = 1.2;
+
+/// Moves the camera smoothly to a target, once it gets there more data is fetched for the new area.
+#[derive(Component)]
+pub struct FlyTo {
+    pub target: Vec2,
+    pub scale: f32,
+    from: Option<(Vec2, f32)>,
+    elapsed: f32,
+}
+
+impl FlyTo {
+    pub fn new(target: Vec2, scale: f32) -> Self {
+        Self { target, scale, from: None, elapsed: 0.0 }
+    }
+
+    /// Fits the world space rect into the window.
+    pub fn to_rect(rect: &WorldSpaceRect, window: &Window, world_space: &WorldSpace) -> Self {
+        let center = Vec2::new((rect.left + rect.right) / 2.0, (rect.bottom + rect.top) / 2.0);
+        let scale = ((rect.right - rect.left).abs() / window.width()).max((rect.top - rect.bottom).abs() / window.height());
+        // Keep to what pancam lets you zoom to
+        Self::new(center, scale.max(camera_scale_for_zoom(MAX_ZOOM, world_space.to_lon_lat(center.into()), world_space)))
+    }
+}
+
+pub fn fly_camera(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut camera: Query<(Entity, &mut Transform, &mut OrthographicProjection, &mut FlyTo)>,
+    mut map_bundle: ResMut<MapBundle>,
+) {
+    for (entity, mut transform, mut projection, mut fly_to) in camera.iter_mut() {
+        let (from, from_scale) = *fly_to.from.get_or_insert((transform.translation.truncate(), projection.scale));
+        fly_to.elapsed += time.delta_secs();
+        let t = (fly_to.elapsed / FLY_TIME).clamp(0.0, 1.0);
+        let t = t * t * (3.0 - 2.0 * t); // Smoothstep so it eases in and out
+
+        let position = from.lerp(fly_to.target, t);
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+        // Zoom in log space so it feels even
+        projection.scale = (from_scale.ln() + (fly_to.scale.ln() - from_scale.ln()) * t).exp();
+
+        if t >= 1.0 {
+            commands.entity(entity).remove::<FlyTo>();
+            map_bundle.get_more_data = true;
+            map_bundle.respawn = true;
+        }
+    }
+}
+
 /// Overflow is the amount of world space that is loaded outside of the window, it is a multiplier of the window size
 pub fn camera_space_to_world_space(
     transform: &GlobalTransform,
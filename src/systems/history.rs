@@ -0,0 +1,289 @@
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_egui::{egui::{self, Color32, RichText}, EguiContexts};
+use std::collections::HashSet;
+
+use rstar::{Envelope, RTree, RTreeObject, AABB};
+
+use crate::{map::{parse_osm_timestamp, FeatureId, FeatureIdSelection, Layer, LayerRegion, LonLat, MapBundle, MapFeature, SpatialIndex, WorldSpace}, webapi::{get_overpass_snapshot, RequestProgress}};
+
+use super::{camera_space_to_world_space, spawn_map_features, ActiveRequests, BatchedTile, MapSender, MapStyle, SettingsOverlay, ShapeTessellator};
+
+/// Marks the entities which are drawn from the historical snapshot rather than the current data.
+#[derive(Component, Clone)]
+pub struct HistoricalFeature;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CompareMode {
+    #[default]
+    Current,
+    Historical,
+    Swipe,      // Historical to the left of the swipe line, current to the right
+}
+
+/// The map as it was at a certain date, kept apart from the current data so the two can be compared.
+#[derive(Resource)]
+pub struct HistoricalView {
+    pub open: bool,
+    pub date_input: String,
+    pub date: Option<String>,           // The date of the loaded snapshot, in the format overpass wants
+    pub features: RTree<MapFeature>,
+    feature_ids: HashSet<FeatureId>,    // Every feature in `features`, so duplicates can be dropped quickly
+    added: Vec<(FeatureId, AABB<[f64; 2]>)>,    // Features which have come in since the entities were last spawned, with where to find them
+    pub spatial_index: SpatialIndex,    // The regions which have been loaded for this date
+    pub mode: CompareMode,
+    pub swipe: f32,                     // Where the swipe line is across the window, from 0 to 1
+    pub get_data: bool,
+    pub respawn: bool,                  // Every entity is spawned again, for when the snapshot is replaced or the mode changes
+    pub error: Option<String>,
+}
+
+impl Default for HistoricalView {
+    fn default() -> Self {
+        HistoricalView {
+            open: false,
+            date_input: "2015-01-01".to_string(),
+            date: None,
+            features: RTree::new(),
+            feature_ids: HashSet::new(),
+            added: Vec::new(),
+            spatial_index: SpatialIndex::new(),
+            mode: CompareMode::Current,
+            swipe: 0.5,
+            get_data: false,
+            respawn: false,
+            error: None,
+        }
+    }
+}
+
+impl HistoricalView {
+    /// Switches the snapshot to a new date, throwing away anything loaded for the old one.
+    fn set_date(&mut self, date: String) {
+        if self.date.as_ref() != Some(&date) {
+            self.features = RTree::new();
+            self.feature_ids.clear();
+            self.added.clear();
+            self.spatial_index = SpatialIndex::new();
+            self.date = Some(date);
+            self.respawn = true;
+        }
+    }
+
+    /// Throws away the snapshot and goes back to showing the current map, its entities go on the next respawn.
+    pub fn clear(&mut self) {
+        self.features = RTree::new();
+        self.feature_ids.clear();
+        self.added.clear();
+        self.spatial_index = SpatialIndex::new();
+        self.date = None;
+        self.mode = CompareMode::Current;
+        self.get_data = false;
+        self.error = None;
+        self.respawn = true;
+    }
+
+    pub fn insert_features(&mut self, date: &str, features: Vec<MapFeature>) {
+        // Chunks for a date we have since moved away from are dropped
+        if self.date.as_deref() != Some(date) {
+            return;
+        }
+        for feature in features {
+            if self.feature_ids.insert(feature.id) {
+                self.added.push((feature.id, feature.envelope()));
+                self.features.insert(feature);
+            }
+        }
+    }
+}
+
+/// Turns `2015-01-01` or a full timestamp into the `2015-01-01T00:00:00Z` form overpass wants.
+fn normalise_date(input: &str) -> Option<String> {
+    let input = input.trim();
+    let date = if input.len() == 10 { format!("{}T00:00:00Z", input) } else { input.to_string() };
+    parse_osm_timestamp(&date)?;
+    Some(date)
+}
+
+pub fn history_panel(
+    mut contexts: EguiContexts,
+    mut history: ResMut<HistoricalView>,
+) {
+    let mut open = history.open;
+    egui::Window::new("History")
+        .open(&mut open)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Date");
+                ui.text_edit_singleline(&mut history.date_input);
+                if ui.button("Load").on_hover_text("Loads the map in view as it was on this date").clicked() {
+                    match normalise_date(&history.date_input) {
+                        Some(date) => {
+                            history.set_date(date);
+                            history.error = None;
+                            history.get_data = true;
+                            if history.mode == CompareMode::Current {
+                                history.mode = CompareMode::Swipe;
+                            }
+                        }
+                        None => history.error = Some("Dates should look like 2015-01-01".to_string()),
+                    }
+                }
+            });
+            if let Some(error) = &history.error {
+                ui.label(RichText::new(error).color(Color32::from_rgb(230, 90, 90)));
+            }
+            if let Some(date) = history.date.clone() {
+                ui.label(format!("Snapshot of {} with {} features", date, history.features.size()));
+            }
+            ui.separator();
+            let mut mode = history.mode;
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut mode, CompareMode::Current, "Current");
+                ui.radio_value(&mut mode, CompareMode::Historical, "Historical");
+                ui.radio_value(&mut mode, CompareMode::Swipe, "Swipe");
+            });
+            if mode != history.mode {
+                history.mode = mode;
+                history.respawn = true;
+            }
+            if history.mode == CompareMode::Swipe {
+                ui.add(egui::Slider::new(&mut history.swipe, 0.0..=1.0).show_value(false).text("Swipe"));
+            }
+        });
+    history.open = open;
+}
+
+/// Fetches the snapshot for the area in view.
+#[allow(clippy::too_many_arguments)]
+pub fn fetch_snapshot(
+    query: Query<&GlobalTransform, With<Camera2d>>,
+    primary_window_query: Query<&Window, With<PrimaryWindow>>,
+    ortho_projection_query: Query<&OrthographicProjection, With<Camera>>,
+    mut history: ResMut<HistoricalView>,
+    overpass_settings: Res<SettingsOverlay>,
+    map_sender: Res<MapSender>,
+    mut active_requests: ResMut<ActiveRequests>,
+    world_space: Res<WorldSpace>,
+) {
+    if !history.get_data {
+        return;
+    }
+    history.get_data = false;
+    let Some(date) = history.date.clone() else { return };
+
+    let camera_transform = query.single();
+    let window = primary_window_query.single();
+    if let Some(viewport) = camera_space_to_world_space(camera_transform, window, ortho_projection_query.single().clone(), 1.25) {
+        let viewport = world_space.rect_to_lon_lat(&viewport);
+        let layers = overpass_settings.get_layers_to_fetch();
+        if history.spatial_index.is_covered(&viewport, &layers) {
+            return;
+        }
+        let missing = history.spatial_index.missing(std::slice::from_ref(&viewport), &layers);
+        let area = RequestProgress::describe_bounds(&viewport);
+        // All of the viewport is loaded once this comes in, so one region will do
+        let covers = LayerRegion::new(viewport, layers);
+        history.spatial_index.insert(covers.clone());
+        let progress = active_requests.track_snapshot(&format!("Snapshot {}", date), area, vec![covers]);
+        let tx = (*map_sender).clone();
+        std::thread::spawn(move || {
+            get_overpass_snapshot(missing, &date, &progress, &tx);
+        });
+    }
+}
+
+/// Spawns the snapshot entities, this runs before `respawn_map` so it follows the same changes to the layers.
+/// The snapshot only has what was in view when it was loaded, so all of it is spawned rather than what is in view.
+/// Everything is spawned again when the snapshot, the compare mode, the layers or the level of detail change,
+/// otherwise only the features which have just come in are.
+#[allow(clippy::too_many_arguments)]
+pub fn respawn_snapshot(
+    mut commands: Commands,
+    mut spawned_layers: Local<Vec<Layer>>,
+    shapes_query: Query<Entity, With<HistoricalFeature>>,
+    overpass_settings: Res<SettingsOverlay>,
+    map_bundle: Res<MapBundle>,
+    mut history: ResMut<HistoricalView>,
+    mut tessellator: ResMut<ShapeTessellator>,
+    map_style: Res<MapStyle>,
+    world_space: Res<WorldSpace>,
+) {
+    let enabled_setting = overpass_settings.get_true_keys_with_category_with_individual();
+    let respawn = history.respawn || map_bundle.redraw || *spawned_layers != enabled_setting;
+    if !respawn && history.added.is_empty() {
+        return;
+    }
+    history.respawn = false;
+    let added = std::mem::take(&mut history.added);
+
+    if respawn {
+        *spawned_layers = enabled_setting;
+        for entity in shapes_query.iter() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+    // There is no point drawing what can't be seen, changing the mode spawns everything again
+    if history.mode == CompareMode::Current {
+        return;
+    }
+
+    let features: Vec<&MapFeature> = if respawn {
+        history.features.iter().collect()
+    } else {
+        added.into_iter()
+            .filter_map(|(id, envelope)| history.features.locate_with_selection_function(FeatureIdSelection { id, envelope }).next())
+            .collect()
+    };
+    spawn_map_features(&mut commands, &mut tessellator, features.into_iter(), &overpass_settings, &map_style.stylesheet, &world_space, HistoricalFeature);
+}
+
+/// Shows either the current or historical entities depending on the compare mode, in swipe mode this
+/// goes by which side of the swipe line the middle of each feature is on.
+#[allow(clippy::too_many_arguments)]
+pub fn update_compare_visibility(
+    mut gizmos: Gizmos,
+    history: Res<HistoricalView>,
+    mut features: Query<(&MapFeature, &mut Visibility, Has<HistoricalFeature>)>,
+    mut batches: Query<&mut Visibility, (With<BatchedTile>, Without<MapFeature>)>,
+    camera_query: Query<&GlobalTransform, With<Camera2d>>,
+    primary_window_query: Query<&Window, With<PrimaryWindow>>,
+    query: Query<&OrthographicProjection, With<Camera>>,
+    world_space: Res<WorldSpace>,
+) {
+    if history.mode == CompareMode::Current && !history.is_changed() {
+        return;
+    }
+
+    let camera_transform = camera_query.single();
+    let window = primary_window_query.single();
+    let projection = query.single();
+    let swipe_x = camera_transform.translation().x + (history.swipe - 0.5) * window.width() * projection.scale;
+    if history.mode == CompareMode::Swipe {
+        let half_height = window.height() * projection.scale / 2.0;
+        let y = camera_transform.translation().y;
+        gizmos.line_2d(Vec2::new(swipe_x, y - half_height), Vec2::new(swipe_x, y + half_height), Color::WHITE);
+    }
+
+    for (feature, mut visibility, historical) in features.iter_mut() {
+        let visible = match history.mode {
+            CompareMode::Current => !historical,
+            CompareMode::Historical => historical,
+            CompareMode::Swipe => {
+                let center = feature.envelope().center();
+                let x = world_space.to_world(LonLat::new(center[0], center[1])).x;
+                (x < swipe_x) == historical
+            }
+        };
+        let new_visibility = if visible { Visibility::Inherited } else { Visibility::Hidden };
+        if *visibility != new_visibility {
+            *visibility = new_visibility;
+        }
+    }
+    // A batch covers a whole tile so it can't be cut by the swipe line, it is only hidden to show the snapshot on its own
+    let batch_visibility = if history.mode == CompareMode::Historical { Visibility::Hidden } else { Visibility::Inherited };
+    for mut visibility in batches.iter_mut() {
+        if *visibility != batch_visibility {
+            *visibility = batch_visibility;
+        }
+    }
+}
@@ -1,11 +1,13 @@
 use bevy::{prelude::*, utils::HashMap, window::PrimaryWindow};
 use bevy_egui::{egui, EguiContexts};
 use bevy_pancam::PanCam;
-use bevy_prototype_lyon::prelude::*;
 
-use crate::map::{MapBundle, MapFeature};
+use geo::Contains;
+use rstar::AABB;
 
-use super::OccupiedScreenSpace;
+use crate::map::{MapBundle, MapFeature, WorldSpace};
+
+use super::{feature_layer, BatchedRendering, OccupiedScreenSpace, SettingsOverlay};
 
 /// Handles keyboard input and updates map features accordingly.
 pub fn handle_keyboard(
@@ -16,6 +18,9 @@ pub fn handle_keyboard(
         // U is being held down
         map_bundle.get_more_data = true;
     }
+    if keys.just_pressed(KeyCode::KeyR) {
+        map_bundle.refresh = true;
+    }
 }
 
 /// Handles mouse input and updates camera and map features accordingly.
@@ -57,13 +62,19 @@ impl Default for PersistentInfoWindows {
 }
 
 /// Checks map information based on mouse input and camera view.
+/// Batched features don't have entities of their own, so they are found through the feature tree instead.
+#[allow(clippy::too_many_arguments)]
 pub fn check_map_info(
     windows: Query<&Window>,
     camera: Query<(&Camera, &GlobalTransform), With<Camera2d>>,
-    shapes: Query<(&Path, &GlobalTransform, &MapFeature)>,
+    shapes: Query<(&MapFeature, &ViewVisibility)>,
+    map_bundle: Res<MapBundle>,
+    batching: Res<BatchedRendering>,
+    overpass_settings: Res<SettingsOverlay>,
     mouse_button: Res<ButtonInput<MouseButton>>,
     mut contexts: EguiContexts,
     mut persistent_info_windows: ResMut<PersistentInfoWindows>,
+    world_space: Res<WorldSpace>,
 ) {
     if mouse_button.just_pressed(MouseButton::Left) {
         let (camera, camera_transform) = camera.single();
@@ -71,27 +82,34 @@ pub fn check_map_info(
 
         if let Some(cursor_pos) = window.cursor_position() {
             let world_position = camera.viewport_to_world_2d(camera_transform, cursor_pos).unwrap();
-            for (path, _transform, feat) in shapes.iter() {
-                let mut vertices: Vec<tess::geom::euclid::Point2D<f32, tess::geom::euclid::UnknownUnit>> = Vec::new();
-                for path in path.0.iter() {
-                    match path {
-                        tess::path::Event::Line { to, from } => {
-                            vertices.push(from);
-                            vertices.push(to);
-                        },
-                        _ => continue,
-                    };
-                }
-                if is_point_in_polygon(&world_position, vertices) {
+            let point = world_space.to_lon_lat(world_position.into());
+            let mut found = false;
+            for (feat, visibility) in shapes.iter() {
+                // Hidden ones are on the other side of the history comparison
+                if visibility.get() && feat.geometry.contains(&geo::Point::new(point.lon, point.lat)) {
                     persistent_info_windows.windows.insert(
                         feat.id.to_string(),
                         feat.properties.to_string(),
                     );
                 
                     // You can add additional logic here to handle the clicks
+                    found = true;
                     break;
                 }
             }
+            if !found && batching.enabled {
+                let enabled = overpass_settings.get_true_keys_with_category_with_individual();
+                let clicked = map_bundle.features
+                    .locate_in_envelope_intersecting(&AABB::from_point([point.lon, point.lat]))
+                    .filter(|feature| feature_layer(feature, &enabled).is_some())
+                    .find(|feature| feature.geometry.contains(&geo::Point::new(point.lon, point.lat)));
+                if let Some(feat) = clicked {
+                    persistent_info_windows.windows.insert(
+                        feat.id.to_string(),
+                        feat.properties.to_string(),
+                    );
+                }
+            }
         }
     }
     let mut windows_to_remove = Vec::new();
@@ -108,24 +126,3 @@ pub fn check_map_info(
         persistent_info_windows.windows.remove(&id);
     }
 }
-
-pub fn is_point_in_polygon(point: &Vec2, vertices:  Vec<tess::geom::euclid::Point2D<f32, tess::geom::euclid::UnknownUnit>>) -> bool {
-    if vertices.len() < 3 {
-        return false;
-    }
-    
-    let mut inside = false;
-    let mut j = vertices.len() - 1;
-    
-    for i in 0..vertices.len() {
-        if ((vertices[i].y > point.y) != (vertices[j].y > point.y)) &&
-            (point.x < (vertices[j].x - vertices[i].x) * (point.y - vertices[i].y) 
-                / (vertices[j].y - vertices[i].y) + vertices[i].x)
-        {
-            inside = !inside;
-        }
-        j = i;
-    }
-    
-    inside
-}
\ No newline at end of file
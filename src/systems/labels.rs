@@ -0,0 +1,58 @@
+use bevy::prelude::*;
+use bevy_egui::{egui::{self, Color32}, EguiContexts};
+
+use crate::map::{LonLat, WorldSpace};
+
+/// The most labels drawn at once, past this they are too crowded to read anyway.
+const MAX_LABELS: usize = 200;
+
+/// Text drawn over a feature, from the `text` rules in the stylesheet.
+#[derive(Component, Clone)]
+pub struct FeatureLabel {
+    pub text: String,
+    pub color: Srgba,
+    pub size: f32,
+    pub position: LonLat,
+}
+
+/// Draws the labels of the features in view, with egui so they stay the same size however far in the camera is.
+/// Bigger labels go first and anything that would overlap a label already drawn is left out.
+pub fn draw_labels(
+    mut contexts: EguiContexts,
+    camera: Query<(&Camera, &GlobalTransform), With<Camera2d>>,
+    labels: Query<(&FeatureLabel, &InheritedVisibility)>,
+    world_space: Res<WorldSpace>,
+) {
+    let Ok((camera, camera_transform)) = camera.get_single() else { return };
+    let ctx = contexts.ctx_mut();
+    let screen = ctx.screen_rect();
+    // The background layer is under the windows and panels, but still over the map
+    let painter = ctx.layer_painter(egui::LayerId::background());
+
+    let mut in_view: Vec<(&FeatureLabel, egui::Pos2)> = labels.iter()
+        .filter(|(_, visibility)| visibility.get())
+        .filter_map(|(label, _)| {
+            let position = Vec2::from(world_space.to_world(label.position)).extend(0.0);
+            let position = camera.world_to_viewport(camera_transform, position).ok()?;
+            let position = egui::pos2(position.x, position.y);
+            screen.contains(position).then_some((label, position))
+        })
+        .collect();
+    in_view.sort_by(|a, b| b.0.size.total_cmp(&a.0.size));
+
+    let mut placed: Vec<egui::Rect> = Vec::new();
+    for (label, position) in in_view {
+        if placed.len() >= MAX_LABELS {
+            break;
+        }
+        let [r, g, b, a] = label.color.to_u8_array();
+        let color = Color32::from_rgba_unmultiplied(r, g, b, a);
+        let galley = painter.layout_no_wrap(label.text.clone(), egui::FontId::proportional(label.size), color);
+        let rect = egui::Align2::CENTER_CENTER.anchor_size(position, galley.size());
+        if placed.iter().any(|other| other.intersects(rect)) {
+            continue;
+        }
+        painter.galley(rect.min, galley, color);
+        placed.push(rect);
+    }
+}
@@ -1,141 +1,406 @@
 
-use bevy::{prelude::*, utils::HashMap, window::PrimaryWindow};
+use std::collections::HashSet;
+
+use bevy::{prelude::*, window::PrimaryWindow};
 use bevy_prototype_lyon::prelude::*;
-use crossbeam_channel::{bounded, Receiver};
-use geo::Intersects;
-use rstar::AABB;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use rstar::{Envelope, RTreeObject, AABB};
+
+use crate::{map::{camera_scale_for_zoom, detail_zoom, is_line, metres_per_world_unit, OsmType, way_layer, way_width, FeatureId, Layer, LonLat, LonLatRect, MapBundle, MapFeature, StyleColor, StyleWidth, Stylesheet, WorldSpace, LayerRegion}, webapi::{get_overpass_data, OverpassMessage, RequestProgress}};
+use super::{apply_diff, batch_groups, camera_space_to_world_space, recolor_mesh, shape_entity, spawn_batch, ActiveRequests, BatchedRendering, BatchedTile, AreaLoader, ChangeHighlights, FeatureLabel, FeatureShape, HistoricalFeature, HistoricalView, MapStyle, SettingsOverlay, ShapeJob, ShapeTessellator};
 
-use crate::{map::{world_space_rect_to_lat_long, MapBundle, MapFeature, WorldSpaceRect, SCALE, STARTING_LONG_LAT}, webapi::get_overpass_data};
-use super::{camera_space_to_world_space, SettingsOverlay};
+/// The viewport and layers the entities were last spawned for.
+#[derive(Default)]
+pub struct SpawnedView {
+    viewport: Option<AABB<[f64; 2]>>,
+    layers: Vec<Layer>,
+}
 
+/// Brings the entities up to date with the map. Features which have come into view or into an enabled layer are spawned,
+/// ones which have left are despawned and everything else is left where it is, so this only costs as much as what has changed.
+/// When the camera and layers haven't changed only the features which have been added or removed are looked at.
+/// With batched rendering on this is done a tile at a time instead.
+#[allow(clippy::too_many_arguments)]
 pub fn respawn_map(
     mut commands: Commands,
-    shapes_query: Query<(Entity, &Path, &GlobalTransform, &MapFeature)>,
+    mut spawned_view: Local<SpawnedView>,
+    shapes_query: Query<(Entity, &MapFeature), Without<HistoricalFeature>>,
+    batches: Query<(Entity, &BatchedTile)>,
+    mut tessellator: ResMut<ShapeTessellator>,
+    batching: Res<BatchedRendering>,
     overpass_settings: Res<SettingsOverlay>,
+    map_style: Res<MapStyle>,
     mut map_bundle: ResMut<MapBundle>,
     camera_query: Query<(&Camera, &GlobalTransform), With<Camera2d>>,
     primary_window_query: Query<&Window, With<PrimaryWindow>>,
     query: Query<&mut OrthographicProjection, With<Camera>>,
+    world_space: Res<WorldSpace>,
 ) {
-    if map_bundle.respawn {
-        info!("Respawning map...");
-        map_bundle.respawn = false;
+    // Batches can't be restyled in place, so they are built again
+    let restyled = batching.enabled && !map_bundle.restyle.is_empty();
+    if !(map_bundle.respawn || map_bundle.redraw || restyled) {
+        return;
+    }
+    map_bundle.respawn = false;
+    let redraw = std::mem::take(&mut map_bundle.redraw);
+    let removed = map_bundle.take_removed();
+    let added = map_bundle.take_added();
+    // `restyle_map` clears these once it has restyled the entities that aren't batched
+    let restyled = if batching.enabled { map_bundle.restyle.clone() } else { Vec::new() };
 
-        for (entity, _, _, _) in shapes_query.iter() {
-            commands.entity(entity).despawn_recursive(); // Use despawn_recursive instead of despawn
+    // Determine the viewport bounds
+    let (_, camera_transform) = camera_query.single();
+    let window = primary_window_query.single();
+    let viewport_aabb = viewport_feature_aabb(camera_transform, window, query.single().clone(), 1.75, &world_space);
+
+    let enabled_setting = overpass_settings.get_true_keys_with_category_with_individual();
+    let mut despawned = 0;
+
+    if batching.enabled {
+        // Everything is looked at again once batching is turned off
+        spawned_view.viewport = None;
+        // The features are all in the batches, so none of their own entities are wanted
+        for (entity, _) in shapes_query.iter() {
+            commands.entity(entity).despawn_recursive();
+            despawned += 1;
+        }
+
+        let groups = batch_groups(&map_bundle, &enabled_setting, &viewport_aabb);
+        let mut built = HashSet::new();
+        for (entity, batch) in batches.iter() {
+            let key = (batch.tile, batch.layer.clone());
+            let up_to_date = !redraw
+                && !restyled.contains(&batch.layer)
+                && groups.get(&key).is_some_and(|features| batch.has_features(features))
+                && !batch.features.iter().any(|id| removed.contains(id));
+            if up_to_date {
+                built.insert(key);
+            } else {
+                commands.entity(entity).despawn_recursive();
+                despawned += 1;
+            }
         }
 
-        let mut batch_commands_closed: Vec<(ShapeBundle, Fill, Stroke, MapFeature)> = Vec::new();
-        let mut batch_commands_open: Vec<(ShapeBundle, Stroke, MapFeature)> = Vec::new();
+        let mut count = 0;
+        for (key, features) in groups {
+            if built.contains(&key) {
+                continue;
+            }
+            // Everything in a layer is the same color, but roads in it can still be different widths
+            let styled: Vec<(MapFeature, FeatureStyle)> = features.into_iter()
+                .filter_map(|feature| Some((feature.clone(), feature_style(feature, &overpass_settings, &enabled_setting, &map_style.stylesheet, &world_space, tessellator.detail, tessellator.zoom)?)))
+                .collect();
+            if styled.is_empty() {
+                continue;
+            }
+            spawn_batch(&mut commands, &mut tessellator, &world_space, key, styled);
+            count += 1;
+        }
+        if count > 0 || despawned > 0 {
+            info!("Respawning map, {} batches built and {} despawned", count, despawned);
+        }
+        return;
+    }
 
+    // Anything left over from when batching was on
+    for (entity, _) in batches.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
 
-        // Determine the viewport bounds
-        let (_, camera_transform) = camera_query.single();
-        let window = primary_window_query.single();
-        let viewport = camera_space_to_world_space(camera_transform, window, query.single().clone(), 1.75).unwrap();
-
-        let viewport_rect = world_space_rect_to_lat_long(viewport, SCALE, STARTING_LONG_LAT.x, STARTING_LONG_LAT.y);
-        let left = viewport_rect.left.min(viewport_rect.right);
-        let right = viewport_rect.left.max(viewport_rect.right);
-        let bottom = viewport_rect.bottom.min(viewport_rect.top);
-        let top = viewport_rect.bottom.max(viewport_rect.top);
-        let viewport_aabb = AABB::from_corners(
-            [bottom as f64, left as f64],
-            [top as f64, right as f64],
-        );
-        let intersection_candidates = map_bundle.features.locate_in_envelope_intersecting(&viewport_aabb).collect::<Vec<_>>();
-
-        let disabled_setting = overpass_settings.get_disabled_categories();
-        let enabled_setting = overpass_settings.get_true_keys_with_category_with_individual();
-
-        // Group features by category and key, the string is thing to look for
-        // (cat, key)
-        let mut feature_groups: HashMap<(String, String), Vec<&MapFeature>> = HashMap::new();
-        
-        for feature in &map_bundle.features {
-            for (cat, key) in &enabled_setting {
-                if !disabled_setting.contains(cat) {
-                    feature_groups.entry((cat.to_string(), key.to_string())).or_default().push(feature);
+    if !redraw && spawned_view.viewport == Some(viewport_aabb) && spawned_view.layers == enabled_setting {
+        // Nothing has moved, so only new data needs looking at. Replaced features are in both, they get a new entity
+        if !removed.is_empty() {
+            for (entity, feature) in shapes_query.iter() {
+                if removed.contains(&feature.id) {
+                    commands.entity(entity).despawn_recursive();
+                    despawned += 1;
                 }
             }
         }
-        
-        for feature in intersection_candidates {
-            let mut fill_color= Some(Srgba { red: 0.4, green: 0.400, blue: 0.400, alpha: 1.0 });
-            let mut stroke_color = Srgba { red: 0.50, green: 0.500, blue: 0.500, alpha: 1.0 };
-            let mut line_width = 1.0;
-            let mut elevation = 1.0;
-            for ((cat, key), _) in &feature_groups {
-                if key != "*" && feature.properties.get(cat.to_lowercase()).map_or(false, |v| *v == *key.to_lowercase()) {
-                                    let color = overpass_settings.categories.get(cat).unwrap().items.get(key).unwrap().1;
-                                    fill_color = Some(Srgba { red: (color.r() as f32) / 255., green: (color.g() as f32) / 255., blue: (color.b() as f32) / 255., alpha: 1.0 });
-                                    stroke_color = Srgba { red: (color.r() as f32) / 210., green: (color.g() as f32) / 210., blue: (color.b() as f32) / 210., alpha: 1.0 };
-                                    if cat == "Highway" || cat == "Railway" {
-                                        fill_color = None;
-                                        line_width = 2.5;
-                                        elevation = 0.;
-                
-                                        // When zoomed out we should make the primary roads bigger, and the motorways even bigger.
-                                        if feature.properties.get("highway").map_or(false, |v| v == "residential" || v == "primary" || v == "secondary" || v == "tertiary") {
-                                            line_width = 5.5;
-                                        }
-                                        
-                
-                                        feature.properties.get("est_width").map_or((), |v| {
-                                            // line_width = v.as_str().unwrap().replace("\"", "").parse::<f64>().unwrap() as f64;
-                                        });
-                                    }
-
-                                    let mut points = feature.get_in_world_space();
-                                    points.pop();                            
-                                        
-                                    let shape = shapes::Polygon {
-                                        points: points.clone(),
-                                        closed: false,
-                                    };
-                        
-                                    if let Some(fill) = fill_color {
-                                        batch_commands_closed.push((
-                                            ShapeBundle {
-                                                path: GeometryBuilder::build_as(&shape),
-                                                transform: Transform::from_xyz(0.0, 0.0, elevation),
-                                                ..default()
-                                            },
-                                            Fill::color(fill),
-                                            Stroke::new(stroke_color, line_width as f32),
-                                            feature.clone(),
-                                        ));
-                                    } else {
-                                        batch_commands_open.push((
-                                            ShapeBundle {
-                                                path: GeometryBuilder::build_as(&shape),
-                                                transform: Transform::from_xyz(0.0, 0.0, elevation),
-                                                ..default()
-                                            },
-                                            Stroke::new(stroke_color, line_width as f32),
-                                            feature.clone(),
-                                        ));
-                                    }
-                                }
-            }
+        let new_features = added.iter()
+            .filter_map(|id| map_bundle.get_feature(*id))
+            .filter(|feature| feature.envelope().intersects(&viewport_aabb) && feature_layer(feature, &enabled_setting).is_some());
+        let count = spawn_map_features(&mut commands, &mut tessellator, new_features, &overpass_settings, &map_style.stylesheet, &world_space, ());
+        if count > 0 || despawned > 0 {
+            info!("Respawning map, {} spawned and {} despawned", count, despawned);
+        }
+        return;
+    }
+    spawned_view.viewport = Some(viewport_aabb);
+    spawned_view.layers = enabled_setting.clone();
+
+    let wanted: HashSet<FeatureId> = map_bundle.features
+        .locate_in_envelope_intersecting(&viewport_aabb)
+        .filter(|feature| feature_layer(feature, &enabled_setting).is_some())
+        .map(|feature| feature.id)
+        .collect();
+
+    let mut spawned: HashSet<FeatureId> = HashSet::new();
+    for (entity, feature) in shapes_query.iter() {
+        // Removed features may have been put back with new geometry, so they are always spawned again
+        if redraw || !wanted.contains(&feature.id) || removed.contains(&feature.id) {
+            commands.entity(entity).despawn_recursive(); // Use despawn_recursive instead of despawn
+            despawned += 1;
+        } else {
+            spawned.insert(feature.id);
+        }
+    }
+
+    let new_features = map_bundle.features
+        .locate_in_envelope_intersecting(&viewport_aabb)
+        .filter(|feature| wanted.contains(&feature.id) && !spawned.contains(&feature.id));
+    let count = spawn_map_features(&mut commands, &mut tessellator, new_features, &overpass_settings, &map_style.stylesheet, &world_space, ());
+    if count > 0 || despawned > 0 {
+        info!("Respawning map, {} spawned and {} despawned", count, despawned);
+    }
+}
+
+/// The viewport as an AABB in the same space as the features, so it can be used to query the feature tree.
+pub fn viewport_feature_aabb(camera_transform: &GlobalTransform, window: &Window, projection: OrthographicProjection, overflow: f32, world_space: &WorldSpace) -> AABB<[f64; 2]> {
+    let viewport = camera_space_to_world_space(camera_transform, window, projection, overflow).unwrap();
+    world_space.rect_to_lon_lat(&viewport).envelope()
+}
+
+/// Roads and railways sized in metres, or with `width: auto`, are drawn at least this many pixels wide so they can still be seen zoomed out.
+const MIN_WAY_PIXELS: f32 = 1.5;
+
+/// Where features on the ground are drawn. Each `layer=*` moves them up or down a step from here, and the
+/// `z-index` from the stylesheet orders them within a layer, so a bridge is always above what it goes over.
+const BASE_ELEVATION: f32 = 0.5;
+const LAYER_STEP: f32 = 0.1;
+const Z_INDEX_STEP: f32 = 0.001;
+const MAX_Z_INDEX: f32 = 30.0;
+/// How far below its layer casings go, every casing on a layer is below every feature on it so junctions join up.
+const CASING_DEPTH: f32 = 0.04;
+
+/// How a feature gets drawn.
+#[derive(Clone)]
+pub struct FeatureStyle {
+    pub fill: Option<Srgba>,
+    pub stroke: Srgba,
+    pub line_width: f32,                    // Nothing is stroked when this is 0
+    pub elevation: f32,
+    pub casing: Option<(Srgba, f32, f32)>,  // The color, width and elevation of the outline drawn under a road
+    pub dashes: Option<Vec<f32>>,           // Alternating dash and gap lengths in world space
+    pub label: Option<FeatureLabel>,
+}
+
+impl FeatureStyle {
+    /// How the casing under a road is drawn, as a feature of its own.
+    pub fn casing_style(&self) -> Option<FeatureStyle> {
+        let (color, width, elevation) = self.casing?;
+        Some(FeatureStyle { fill: None, stroke: color, line_width: width, elevation, casing: None, dashes: None, label: None })
+    }
+}
+
+/// Marks the entity that draws a road's casing, which is kept apart from the road so it can go under all of them.
+#[derive(Component, Clone)]
+pub struct Casing;
+
+/// Works out the style of a feature from the stylesheet, none if it isn't in an enabled layer.
+/// `enabled` is the (category, key) pairs from `get_true_keys_with_category_with_individual`, so it is only worked out once per spawn.
+/// The stylesheet is matched at the zoom in the middle of the level of `detail`, which is also what pixel widths are for.
+/// Roads are kept from getting too narrow to see at `zoom`, which is where the camera is.
+pub fn feature_style(
+    feature: &MapFeature,
+    overpass_settings: &SettingsOverlay,
+    enabled: &[(String, String)],
+    stylesheet: &Stylesheet,
+    world_space: &WorldSpace,
+    detail: usize,
+    zoom: f64,
+) -> Option<FeatureStyle> {
+    let (cat, key) = feature_layer(feature, enabled)?;
+
+    let color = overpass_settings.categories.get(cat)?.items.get(key)?.1;
+    let layer_color = Srgba::rgb_u8(color.r(), color.g(), color.b());
+    let resolve = |color: Option<StyleColor>, opacity: Option<f32>| match color.unwrap_or(StyleColor::Layer) {
+        StyleColor::Rgba(color) => Some(color.with_alpha(color.alpha * opacity.unwrap_or(1.0))),
+        StyleColor::Layer => Some(layer_color.with_alpha(opacity.unwrap_or(1.0))),
+        StyleColor::None => None,
+    };
+
+    let computed = stylesheet.style(feature, detail_zoom(detail));
+    let center = feature.envelope().center();
+    let center = LonLat::new(center[0], center[1]);
+    let pixel = camera_scale_for_zoom(detail_zoom(detail), center, world_space);
+    let min_way_width = MIN_WAY_PIXELS * camera_scale_for_zoom(zoom, center, world_space);
+    let line = is_line(feature);
+    let to_world = |width: StyleWidth| match width {
+        StyleWidth::Pixels(pixels) => pixels * pixel,
+        StyleWidth::Metres(metres) if line => ((metres as f64 / metres_per_world_unit(center, world_space)) as f32).max(min_way_width),
+        StyleWidth::Metres(metres) => (metres as f64 / metres_per_world_unit(center, world_space)) as f32,
+        StyleWidth::Auto => way_width(feature).map_or(pixel, |metres| ((metres / metres_per_world_unit(center, world_space)) as f32).max(min_way_width)),
+    };
+
+    let layer_elevation = BASE_ELEVATION + way_layer(feature) as f32 * LAYER_STEP;
+    let z_index = computed.z_index.unwrap_or(0.0).clamp(-MAX_Z_INDEX, MAX_Z_INDEX);
+    let line_width = to_world(computed.width.unwrap_or(StyleWidth::Pixels(1.0)));
+    let stroke = resolve(computed.color, computed.opacity);
+
+    // Casings are the line's own color but darker, unless the stylesheet says otherwise
+    let casing = computed.casing_width.map(|casing_width| {
+        let stroke = stroke.unwrap_or(Srgba::NONE);
+        let darker = Srgba { red: stroke.red * 0.55, green: stroke.green * 0.55, blue: stroke.blue * 0.55, alpha: 1.0 };
+        let color = match computed.casing_color {
+            Some(color) => resolve(Some(color), computed.casing_opacity).unwrap_or(Srgba::NONE),
+            None => darker.with_alpha(computed.casing_opacity.unwrap_or(1.0)),
+        };
+        let casing_width = if casing_width == StyleWidth::Auto { pixel } else { to_world(casing_width) };
+        (color, line_width + 2.0 * casing_width, layer_elevation - CASING_DEPTH + z_index * Z_INDEX_STEP / 10.0)
+    });
+
+    let label = computed.text.as_ref()
+        .and_then(|key| feature.properties.get(key))
+        .and_then(|text| text.as_str())
+        .map(|text| FeatureLabel {
+            text: text.to_string(),
+            color: computed.text_color.and_then(|color| resolve(Some(color), None)).unwrap_or(Srgba::WHITE),
+            size: computed.font_size.unwrap_or(12.0),
+            position: center,
+        });
+
+    Some(FeatureStyle {
+        fill: computed.fill_color.and_then(|fill| resolve(Some(fill), computed.fill_opacity)),
+        stroke: stroke.unwrap_or(Srgba::NONE),
+        // `color: none` has no stroke at all, the casing is still as wide as the line would have been
+        line_width: if stroke.is_some() { line_width } else { 0.0 },
+        elevation: layer_elevation + z_index * Z_INDEX_STEP,
+        casing,
+        dashes: computed.dashes.map(|dashes| dashes.into_iter().map(|dash| dash * pixel).collect()),
+        label,
+    })
+}
+
+/// The enabled road and railway layers which are sized in metres, their narrowest ways change width as the camera zooms
+/// to stay a minimum number of pixels across. Goes by how the stylesheet sizes a way with nothing but the layer's tag.
+pub fn zoom_sized_layers(enabled: &[Layer], stylesheet: &Stylesheet, detail: usize) -> Vec<Layer> {
+    enabled.iter()
+        .filter(|(cat, key)| {
+            let way = MapFeature::from_lon_lats(FeatureId(OsmType::Way, 0), serde_json::json!({ cat.to_lowercase(): key.to_lowercase() }), []);
+            is_line(&way) && matches!(stylesheet.style(&way, detail_zoom(detail)).width, Some(StyleWidth::Auto | StyleWidth::Metres(_)))
+        })
+        .cloned()
+        .collect()
+}
+
+/// The first enabled layer a feature is in, which is the one it is styled by.
+pub fn feature_layer<'a>(feature: &MapFeature, enabled: &'a [Layer]) -> Option<&'a Layer> {
+    enabled.iter().find(|layer| is_in_layer(feature, layer))
+}
+
+/// Whether a feature is tagged with the key of a layer, `*` layers match on the category alone so they don't count.
+pub fn is_in_layer(feature: &MapFeature, (cat, key): &Layer) -> bool {
+    key != "*" && feature.properties.get(cat.to_lowercase()).is_some_and(|v| *v == *key.to_lowercase())
+}
+
+type RestyledShape = (Entity, &'static MapFeature, &'static Mesh2d, &'static FeatureShape, Has<Casing>, Option<&'static mut FeatureLabel>);
+
+/// Updates the colors and widths of the entities in layers which have been restyled. Colors are changed in the meshes
+/// there already are, only a change of width needs them tessellating again, so dragging a color picker stays smooth however much is loaded.
+/// Labels are given their new colors too.
+pub fn restyle_map(
+    mut map_bundle: ResMut<MapBundle>,
+    overpass_settings: Res<SettingsOverlay>,
+    map_style: Res<MapStyle>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut tessellator: ResMut<ShapeTessellator>,
+    mut shapes: Query<RestyledShape>,
+    world_space: Res<WorldSpace>,
+) {
+    if map_bundle.restyle.is_empty() {
+        return;
+    }
+    let restyled = std::mem::take(&mut map_bundle.restyle);
+    let enabled_setting = overpass_settings.get_true_keys_with_category_with_individual();
+
+    let mut jobs = Vec::new();
+    for (entity, feature, mesh, shape, casing, label) in shapes.iter_mut() {
+        if !restyled.iter().any(|layer| is_in_layer(feature, layer)) {
+            continue;
+        }
+        let Some(style) = feature_style(feature, &overpass_settings, &enabled_setting, &map_style.stylesheet, &world_space, tessellator.detail, tessellator.zoom) else { continue };
+        // Labels can be the layer's color too
+        if let (Some(mut label), Some(restyled)) = (label, style.label.clone()) {
+            *label = restyled;
         }
+        let Some(style) = (if casing { style.casing_style() } else { Some(style) }) else { continue };
 
-        commands.spawn_batch(batch_commands_closed);
-        commands.spawn_batch(batch_commands_open);
+        if shape.line_width != style.line_width {
+            jobs.push(ShapeJob { entity, features: vec![(feature.clone(), style)] });
+        } else if let Some(mesh) = meshes.get_mut(&mesh.0) {
+            recolor_mesh(mesh, shape.fill_vertices, &style);
+        }
     }
+    tessellator.submit(jobs, &world_space);
 }
 
-fn is_feature_in_viewport(feature: &MapFeature, viewport: &WorldSpaceRect) -> bool {
-    let viewport_rect = geo::Rect::new(
-        geo::Coord { x: viewport.left as f64, y: viewport.bottom as f64 },
-        geo::Coord { x: viewport.right as f64, y: viewport.top as f64 },
-    );
-    feature.geometry.intersects(&viewport_rect)
+/// Spawns the features that are in an enabled layer, `extra` is added to every one of them.
+/// Roads get a second entity for their casing. Their meshes are tessellated in the background and turn up over the next few frames.
+/// Gives back how many features were spawned.
+pub fn spawn_map_features<'a, B: Bundle + Clone>(
+    commands: &mut Commands,
+    tessellator: &mut ShapeTessellator,
+    features: impl Iterator<Item = &'a MapFeature>,
+    overpass_settings: &SettingsOverlay,
+    stylesheet: &Stylesheet,
+    world_space: &WorldSpace,
+    extra: B,
+) -> usize {
+    let mut jobs = Vec::new();
+    let mut count = 0;
+
+    let enabled_setting = overpass_settings.get_true_keys_with_category_with_individual();
+    for feature in features {
+        let Some(style) = feature_style(feature, overpass_settings, &enabled_setting, stylesheet, world_space, tessellator.detail, tessellator.zoom) else { continue };
+
+        if let Some(casing) = style.casing_style() {
+            let entity = commands.spawn((
+                shape_entity(tessellator, casing.elevation),
+                FeatureShape::default(),
+                Casing,
+                feature.clone(),
+                extra.clone(),
+            )).id();
+            jobs.push(ShapeJob { entity, features: vec![(feature.clone(), casing)] });
+        }
+        let entity = commands.spawn((
+            shape_entity(tessellator, style.elevation),
+            FeatureShape::default(),
+            feature.clone(),
+            extra.clone(),
+        )).id();
+        if let Some(label) = style.label.clone() {
+            commands.entity(entity).insert(label);
+        }
+        jobs.push(ShapeJob { entity, features: vec![(feature.clone(), style)] });
+        count += 1;
+    }
+
+    tessellator.submit(jobs, world_space);
+    count
 }
 
+/// The most regions asked for in one overpass query, each is a bbox for every layer missing from it.
+const MAX_REGIONS_PER_REQUEST: usize = 16;
+
 #[derive(Resource, Deref)]
-pub struct MapReceiver(Receiver<Vec<MapFeature>>);
+pub struct MapReceiver(Receiver<OverpassMessage>);
 
+/// Every worker thread gets a clone of this, so everything comes back through the one receiver.
+#[derive(Resource, Deref)]
+pub struct MapSender(Sender<OverpassMessage>);
+
+pub fn map_channel() -> (MapSender, MapReceiver) {
+    let (tx, rx) = bounded::<OverpassMessage>(10);
+    (MapSender(tx), MapReceiver(rx))
+}
+
+/// The dark background drawn under a region while it loads, kept so it can be removed along with the region.
+#[derive(Component)]
+pub struct RegionBackground(pub LonLatRect);
+
+#[allow(clippy::too_many_arguments)]
 pub fn bbox_system(
     mut commands: Commands,
     query: Query<(&Camera, &GlobalTransform), With<Camera2d>>,
@@ -143,63 +408,117 @@ pub fn bbox_system(
     ortho_projection_query: Query<&mut OrthographicProjection, With<Camera>>,
     mut map_bundle: ResMut<MapBundle>,
     overpass_settings: ResMut<SettingsOverlay>,
+    map_sender: Res<MapSender>,
+    mut active_requests: ResMut<ActiveRequests>,
+    world_space: Res<WorldSpace>,
 ) {
     if map_bundle.get_more_data {
         map_bundle.get_more_data = false;
         let (camera, camera_transform) = query.single();
         let window = primary_window_query.single();
 
-        if let Some(viewport) = camera_space_to_world_space(camera_transform, window, ortho_projection_query.single().clone(), 1.25) {
-            // Here we need to go through the bounding boxes and check if we have already gotten this bounding box 
-            if !map_bundle.map_points.spatial_index.is_covered(&viewport) {
-                let (tx, rx) = bounded::<Vec<MapFeature>>(10);
-                let tx_clone = tx.clone();
-                let mut map_bundle_clone = map_bundle.clone();
-                let mut overpass_settings_clone = overpass_settings.clone();
-                map_bundle.map_points.spatial_index.insert(viewport.clone());
-                let converted_bounding_box = world_space_rect_to_lat_long(viewport.clone(), SCALE, STARTING_LONG_LAT.x, STARTING_LONG_LAT.y);
-                
-
-                std::thread::spawn(move || {
-                    //tx.send(get_map_data("green-belt.geojson").unwrap());
-
-                    tx.send(get_overpass_data(vec![converted_bounding_box], &mut map_bundle_clone, &mut overpass_settings_clone));
-                });
-
-                let shape = shapes::RoundedPolygon {
-                    points: vec![
-                        Vec2::new(viewport.left, viewport.bottom),
-                        Vec2::new(viewport.right, viewport.bottom),
-                        Vec2::new(viewport.right, viewport.top),
-                        Vec2::new(viewport.left, viewport.top),
-                    ],
-                    radius: 25.0,
-                    closed: true,
-                };
-                commands.spawn((ShapeBundle {
-                    path: GeometryBuilder::build_as(&shape),
-                    transform: Transform::from_xyz(0.0, 0.0, -0.1),
-                    ..default()
-                },
-                    Fill::color(Srgba {red: 0.071, green: 0.071, blue: 0.071, alpha: 1.0 })
-                ));
-                commands.insert_resource(MapReceiver(rx));
+        let Some(viewport) = camera_space_to_world_space(camera_transform, window, ortho_projection_query.single().clone(), 1.25) else {
+            error!("Failed to convert camera space to world space");
+            return;
+        };
+        let viewport = world_space.rect_to_lon_lat(&viewport);
 
-            } else {
-                error!("Failed to convert camera space to world space");
+        // Only ask for what hasn't been loaded, this also gets any layers which have just been turned on for everywhere we have seen.
+        // Regions which line up are joined first, so a long session doesn't turn into a query with a bbox for every one
+        let layers = overpass_settings.get_layers_to_fetch();
+        let mut rects = LonLatRect::merge_adjacent(map_bundle.map_points.spatial_index.regions());
+        rects.insert(0, viewport);
+        let missing = map_bundle.map_points.spatial_index.missing(&rects, &layers);
+        if missing.is_empty() {
+            info!("Everything in view has already been loaded");
+            return;
+        }
+
+        map_bundle.map_points.spatial_index.insert_vec(missing.clone());
+        let mut uncovered: Vec<LonLatRect> = Vec::new();
+        for region in &missing {
+            if !uncovered.contains(&region.rect) {
+                uncovered.push(region.rect);
             }
         }
+        // When only some of the layers are missing they have just been turned on
+        let kind = if missing.iter().all(|region| region.layers.len() == layers.len()) { "Viewport" } else { "Layers" };
+        let described = RequestProgress::describe_bounds(&viewport);
+
+        // Overpass gives up on queries which are too big, so lots of regions are asked for a few at a time
+        let requests: Vec<&[LayerRegion]> = missing.chunks(MAX_REGIONS_PER_REQUEST).collect();
+        for (i, chunk) in requests.iter().enumerate() {
+            let mut area = described.clone();
+            if requests.len() > 1 {
+                area.push_str(&format!(" (part {} of {})", i + 1, requests.len()));
+            } else if uncovered.len() > 1 {
+                area.push_str(&format!(" ({} parts)", uncovered.len()));
+            }
+            let progress = active_requests.track(kind, area, chunk.to_vec());
+            let regions = chunk.to_vec();
+            let tx = map_sender.0.clone();
+            std::thread::spawn(move || {
+                get_overpass_data(regions, &progress, &tx);
+            });
+        }
+
+        for rect in uncovered {
+            spawn_region_background(&mut commands, &world_space, rect);
+        }
     }
 }
 
+pub fn spawn_region_background(commands: &mut Commands, world_space: &WorldSpace, rect: LonLatRect) {
+    let drawn = world_space.rect_to_world(rect);
+    let shape = shapes::RoundedPolygon {
+        points: vec![
+            Vec2::new(drawn.left, drawn.bottom),
+            Vec2::new(drawn.right, drawn.bottom),
+            Vec2::new(drawn.right, drawn.top),
+            Vec2::new(drawn.left, drawn.top),
+        ],
+        radius: 25.0,
+        closed: true,
+    };
+    commands.spawn((ShapeBundle {
+        path: GeometryBuilder::build_as(&shape),
+        transform: Transform::from_xyz(0.0, 0.0, -0.1),
+        ..default()
+    },
+        Fill::color(Srgba {red: 0.071, green: 0.071, blue: 0.071, alpha: 1.0 }),
+        RegionBackground(rect),
+    ));
+}
+
 pub fn read_map_receiver(
     map_receiver: Res<MapReceiver>,
     mut map_bundle: ResMut<MapBundle>,
+    mut change_highlights: ResMut<ChangeHighlights>,
+    mut history: ResMut<HistoricalView>,
+    mut area_loader: ResMut<AreaLoader>,
+    mut active_requests: ResMut<ActiveRequests>,
+    world_space: Res<WorldSpace>,
 ) {
-    if let Ok(v) = map_receiver.0.try_recv() {
-        for feature in &v {
-            map_bundle.features.insert(feature.clone());
+    // Responses are streamed in chunks, so take everything that has arrived since last time
+    for message in map_receiver.0.try_iter() {
+        match message {
+            OverpassMessage::Features(v) => {
+                for feature in v {
+                    map_bundle.insert_feature(feature);
+                }
+                map_bundle.respawn = true;
+            }
+            OverpassMessage::Source(source) => map_bundle.map_points.sources.push(source),
+            OverpassMessage::Diff { bounds, diff } => apply_diff(&mut map_bundle, &mut change_highlights, bounds, diff),
+            OverpassMessage::Snapshot { date, features } => history.insert_features(&date, features),
+            OverpassMessage::Area { id, boundary, layers } => {
+                // Nothing is marked as loaded for a request which has already been cancelled
+                if active_requests.is_running(id) {
+                    let covers = area_loader.add_area(&mut map_bundle, &world_space, boundary, layers);
+                    active_requests.add_covers(id, covers);
+                }
+            }
+            OverpassMessage::Done(id) => active_requests.finish(id),
         }
-        map_bundle.respawn = true;
     }
 }
\ No newline at end of file
@@ -6,6 +6,22 @@ mod debug;
 mod overpass;
 mod overpass_types;
 mod settings;
+mod provenance;
+mod refresh;
+mod history;
+mod search;
+mod whats_here;
+mod area;
+mod activity;
+mod budget;
+mod origin;
+mod start;
+mod scale_bar;
+mod reproject;
+mod batch;
+mod tessellate;
+mod style;
+mod labels;
 
 pub use camera::*;
 pub use map::*;
@@ -13,4 +29,20 @@ pub use interaction::*;
 pub use debug::*;
 pub use settings::*;
 pub use overpass::*;
-pub use overpass_types::*;
\ No newline at end of file
+pub use overpass_types::*;
+pub use provenance::*;
+pub use refresh::*;
+pub use history::*;
+pub use search::*;
+pub use whats_here::*;
+pub use area::*;
+pub use activity::*;
+pub use budget::*;
+pub use origin::*;
+pub use start::*;
+pub use scale_bar::*;
+pub use reproject::*;
+pub use batch::*;
+pub use tessellate::*;
+pub use style::*;
+pub use labels::*;
\ No newline at end of file
@@ -0,0 +1,47 @@
+use bevy::prelude::*;
+
+use crate::map::{LonLat, MapFeature, WorldSpace};
+
+use super::{BatchedTile, FlyTo, RegionBackground};
+
+/// How far the camera can get from the world origin before the origin is moved to it, in world space.
+/// At this distance f32 is still good to a couple of centimetres.
+const REBASE_DISTANCE: f32 = 250_000.0;
+
+/// The entities which are drawn in world space and need moving with the origin.
+pub type InWorldSpace = (Or<(With<MapFeature>, With<RegionBackground>, With<BatchedTile>)>, Without<Camera2d>);
+
+/// Keeps the world origin near the camera, so anywhere on earth is drawn as precisely as the starting point.
+/// When the camera gets too far away the origin is moved to it and everything in world space is moved back by the same amount.
+/// Everything else is kept in lon and lat, so only the camera and the entities need moving.
+/// This runs first so anything spawned this frame is already relative to the new origin.
+pub fn rebase_world_origin(
+    mut camera: Query<&mut Transform, (With<Camera2d>, Without<FlyTo>)>,
+    mut entities: Query<&mut Transform, InWorldSpace>,
+    mut world_space: ResMut<WorldSpace>,
+) {
+    // Wait for the camera to stop flying, otherwise this would happen every frame on the way
+    let Ok(mut camera_transform) = camera.get_single_mut() else { return };
+    let position = camera_transform.translation.truncate();
+    if position.length() < REBASE_DISTANCE {
+        return;
+    }
+
+    let origin = world_space.to_lon_lat(position.into());
+    let delta = set_world_origin(&mut world_space, origin, &mut entities);
+    camera_transform.translation += delta.extend(0.0);
+}
+
+/// Moves the world origin to `origin`, along with the entities so they stay where they are on the earth.
+/// Gives back how far they were moved, for anything else which is in world space such as the camera.
+pub fn set_world_origin(world_space: &mut WorldSpace, origin: LonLat, entities: &mut Query<&mut Transform, InWorldSpace>) -> Vec2 {
+    // Work out exactly where the new origin is before moving, so everything moves by the same amount
+    let delta = -Vec2::from(world_space.to_world(origin));
+    *world_space = world_space.with_origin(origin);
+    info!("Moved the world origin to {:.5}, {:.5}", origin.lat, origin.lon);
+
+    for mut transform in entities.iter_mut() {
+        transform.translation += delta.extend(0.0);
+    }
+    delta
+}
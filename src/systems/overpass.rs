@@ -1,10 +1,11 @@
 use bevy::prelude::*;
 use crate::map::MapBundle;
-use super::SettingsOverlay;
+use super::{SettingsOverlay, StartLocation};
 
 pub fn spawn_starting_point(
     mut map_bundle: ResMut<MapBundle>,
     mut overpass_settings: ResMut<SettingsOverlay>,
+    start_location: Res<StartLocation>,
 ) 
 {
     if let Some(category) = overpass_settings.categories.get_mut("Highway") {
@@ -15,5 +16,6 @@ pub fn spawn_starting_point(
         category.all = true;
         category.set_children(true);
     }
-    map_bundle.get_more_data = true;
+    // Otherwise this waits until the camera has been moved to the start location
+    map_bundle.get_more_data = !start_location.is_locating();
 }
\ No newline at end of file
@@ -3,6 +3,8 @@ use std::collections::BTreeMap;
 use bevy::prelude::*;
 use bevy_egui::egui::{self, Color32};
 
+use crate::map::Layer;
+
 #[derive(Resource, Clone)]
 pub struct SettingsOverlay {
     // String = cat name, Category = data and children
@@ -884,6 +886,14 @@ impl SettingsOverlay {
             }).collect::<Vec<_>>()
     }
 
+    /// The enabled layers which can be asked for from overpass, this is what coverage is tracked against.
+    pub fn get_layers_to_fetch(&self) -> Vec<Layer> {
+        self.get_true_keys_with_category()
+            .into_iter()
+            .filter(|(_, key)| key != "n/a")
+            .collect()
+    }
+
     /// Returns a hashmap of the true keys with their category and key
     pub fn get_true_keys_with_category_with_individual(&self) -> Vec<(String, String)> {
         self.categories.iter()
@@ -917,16 +927,5 @@ impl SettingsOverlay {
         keys
     }
     */
-
-    pub fn get_disabled_categories(&self) -> Vec<String> {
-        self.categories.iter()
-            .filter_map(|(category_name, category)| {
-                if category.disabled {
-                    Some(category_name.clone())
-                } else {
-                    None
-                }
-            }).collect::<Vec<_>>()
-    }
 }
 
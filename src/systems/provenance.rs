@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_egui::{egui::{self, Color32, RichText}, EguiContexts};
+
+use crate::map::{MapBundle, WorldSpace};
+
+use super::{MemoryBudget, OccupiedScreenSpace};
+
+/// State of the data sources window.
+#[derive(Resource)]
+pub struct ProvenanceSettings {
+    pub open: bool,
+    pub stale_after_hours: f32, // OSM data older than this gets marked as stale
+}
+
+impl Default for ProvenanceSettings {
+    fn default() -> Self {
+        ProvenanceSettings {
+            open: false,
+            stale_after_hours: 24.0,
+        }
+    }
+}
+
+impl ProvenanceSettings {
+    pub fn stale_after(&self) -> Duration {
+        Duration::from_secs_f32(self.stale_after_hours.max(0.0) * 3600.0)
+    }
+}
+
+/// Lists where every loaded region came from, how old it is and under what licence.
+pub fn provenance_panel(
+    mut contexts: EguiContexts,
+    mut provenance_settings: ResMut<ProvenanceSettings>,
+    mut map_bundle: ResMut<MapBundle>,
+    mut budget: ResMut<MemoryBudget>,
+) {
+    let mut open = provenance_settings.open;
+    egui::Window::new("Data sources")
+        .open(&mut open)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Stale after (hours)");
+                ui.add(egui::DragValue::new(&mut provenance_settings.stale_after_hours).range(0.0..=8760.0));
+            });
+            if ui.button("Refresh").on_hover_text("Fetches what has changed in each region since its data was loaded (R)").clicked() {
+                map_bundle.refresh = true;
+            }
+            ui.collapsing("Memory budget", |ui| {
+                ui.label(format!(
+                    "{} features loaded, about {:.1} MB",
+                    map_bundle.features.size(),
+                    map_bundle.feature_bytes() as f32 / (1024.0 * 1024.0),
+                ));
+                ui.horizontal(|ui| {
+                    ui.label("Max features");
+                    ui.add(egui::DragValue::new(&mut budget.max_features).range(1000..=10_000_000).speed(1000));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Max memory (MB)");
+                    ui.add(egui::DragValue::new(&mut budget.max_megabytes).range(16.0..=16384.0));
+                });
+                ui.label(RichText::new("The regions looked at longest ago are unloaded when either is reached").small());
+            });
+            ui.separator();
+            if map_bundle.map_points.sources.is_empty() {
+                ui.label("Nothing has been loaded yet");
+            }
+            let stale_after = provenance_settings.stale_after();
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for (i, source) in map_bundle.map_points.sources.iter().enumerate() {
+                    let stale = source.is_stale(stale_after);
+                    let title = if stale {
+                        RichText::new(format!("Region {} (stale)", i + 1)).color(Color32::from_rgb(230, 140, 40))
+                    } else {
+                        RichText::new(format!("Region {}", i + 1))
+                    };
+                    ui.collapsing(title, |ui| {
+                        let bounds = &source.bounds;
+                        ui.label(format!("Bounds: {:.5}, {:.5} to {:.5}, {:.5}", bounds.min.lat, bounds.min.lon, bounds.max.lat, bounds.max.lon));
+                        ui.label(format!("Source: {}", source.source));
+                        ui.label(format!("Generator: {}", source.generator.as_deref().unwrap_or("unknown")));
+                        match source.age() {
+                            Some(age) => ui.label(format!("Data from: {} ({} ago)", source.timestamp.as_deref().unwrap_or_default(), format_age(age))),
+                            None => ui.label("Data from: unknown"),
+                        };
+                        ui.label(format!("Licence: {}", source.copyright.as_deref().unwrap_or("ODbL")));
+                    });
+                }
+            });
+        });
+    provenance_settings.open = open;
+}
+
+/// Outlines the regions whose data is older than the stale threshold.
+pub fn draw_stale_regions(
+    mut gizmos: Gizmos,
+    provenance_settings: Res<ProvenanceSettings>,
+    map_bundle: Res<MapBundle>,
+    world_space: Res<WorldSpace>,
+) {
+    let stale_after = provenance_settings.stale_after();
+    for source in map_bundle.map_points.sources.iter().filter(|s| s.is_stale(stale_after)) {
+        let rect = world_space.rect_to_world(source.bounds);
+        gizmos.rect_2d(Vec2::from(rect.center()), Vec2::new(rect.width(), rect.height()), Srgba::new(0.9, 0.55, 0.15, 1.0));
+    }
+}
+
+/// Always shows a small OSM attribution in the bottom left corner of the map.
+pub fn draw_attribution(
+    mut contexts: EguiContexts,
+    occupied_screen_space: Res<OccupiedScreenSpace>,
+) {
+    egui::Area::new(egui::Id::new("attribution"))
+        .anchor(egui::Align2::LEFT_BOTTOM, [occupied_screen_space.left + 6.0, -4.0])
+        .interactable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(RichText::new("© OpenStreetMap contributors, ODbL").small().color(Color32::from_rgb(200, 200, 200)));
+        });
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m", secs / 60)
+    } else if secs < 86400 {
+        format!("{}h", secs / 3600)
+    } else {
+        format!("{}d", secs / 86400)
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::SystemTime;
+
+use bevy::prelude::*;
+
+use crate::{map::{LonLat, LonLatRect, MapBundle, MapFeature, OsmChange, OsmDiff, WorldSpace}, webapi::{get_overpass_diff, RequestProgress}};
+
+use super::{ActiveRequests, MapSender, SettingsOverlay};
+
+/// How long a changed feature stays highlighted for, in seconds.
+const HIGHLIGHT_TIME: f32 = 6.0;
+
+/// Outlines of features which have just been changed by a refresh.
+#[derive(Resource, Default)]
+pub struct ChangeHighlights {
+    pub highlights: Vec<ChangeHighlight>,
+}
+
+pub struct ChangeHighlight {
+    pub points: Vec<LonLat>,
+    pub color: Srgba,
+    pub remaining: f32,
+}
+
+impl ChangeHighlights {
+    fn push(&mut self, feature: &MapFeature, color: Srgba) {
+        self.highlights.push(ChangeHighlight {
+            points: feature.lon_lats().collect(),
+            color,
+            remaining: HIGHLIGHT_TIME,
+        });
+    }
+}
+
+/// Asks overpass for an augmented diff of each loaded region since the time of its data.
+pub fn refresh_map_data(
+    mut map_bundle: ResMut<MapBundle>,
+    overpass_settings: Res<SettingsOverlay>,
+    map_sender: Res<MapSender>,
+    mut active_requests: ResMut<ActiveRequests>,
+) {
+    if map_bundle.refresh {
+        map_bundle.refresh = false;
+
+        // The same region can have been fetched more than once, only ask for it from the oldest timestamp
+        let mut regions: Vec<(LonLatRect, String)> = Vec::new();
+        for source in &map_bundle.map_points.sources {
+            let Some(timestamp) = &source.timestamp else { continue };
+            match regions.iter_mut().find(|(bounds, _)| *bounds == source.bounds) {
+                Some((_, since)) => {
+                    if timestamp < since {
+                        *since = timestamp.clone();
+                    }
+                }
+                None => regions.push((source.bounds, timestamp.clone())),
+            }
+        }
+        if regions.is_empty() {
+            info!("Nothing to refresh");
+            return;
+        }
+
+        // They are done one after another, so the later ones sit in the queue
+        let regions: Vec<_> = regions
+            .into_iter()
+            .map(|(bounds, since)| {
+                let progress = active_requests.track("Refresh", RequestProgress::describe_bounds(&bounds), Vec::new());
+                (bounds, since, progress)
+            })
+            .collect();
+        let overpass_settings_clone = overpass_settings.clone();
+        let tx = (*map_sender).clone();
+        std::thread::spawn(move || {
+            for (bounds, since, progress) in regions {
+                get_overpass_diff(bounds, &since, &overpass_settings_clone, &progress, &tx);
+            }
+        });
+    }
+}
+
+/// Applies the creations, modifications and deletions in a diff to the map and highlights them.
+pub fn apply_diff(map_bundle: &mut MapBundle, change_highlights: &mut ChangeHighlights, bounds: LonLatRect, diff: OsmDiff) {
+    info!("Applying {} changes", diff.changes.len());
+    for change in diff.changes {
+        match change {
+            OsmChange::Create(feature) => {
+                map_bundle.remove_feature(feature.id);
+                change_highlights.push(&feature, Srgba::new(0.3, 0.9, 0.3, 1.0));
+                map_bundle.insert_feature(feature);
+            }
+            OsmChange::Modify { new } => {
+                map_bundle.remove_feature(new.id);
+                change_highlights.push(&new, Srgba::new(0.95, 0.8, 0.2, 1.0));
+                map_bundle.insert_feature(new);
+            }
+            OsmChange::Delete { id, old } => {
+                if let Some(old) = map_bundle.remove_feature(id).or(old) {
+                    change_highlights.push(&old, Srgba::new(0.9, 0.25, 0.25, 1.0));
+                }
+            }
+        }
+    }
+
+    // The region is now up to date with the diff
+    for source in map_bundle.map_points.sources.iter_mut().filter(|s| s.bounds == bounds) {
+        if diff.timestamp.is_some() {
+            source.timestamp = diff.timestamp.clone();
+        }
+        source.fetched_at = SystemTime::now();
+    }
+    map_bundle.respawn = true;
+}
+
+/// Draws the outlines of recently changed features, fading them out over time.
+pub fn draw_change_highlights(
+    mut gizmos: Gizmos,
+    time: Res<Time>,
+    mut change_highlights: ResMut<ChangeHighlights>,
+    world_space: Res<WorldSpace>,
+) {
+    if change_highlights.highlights.is_empty() {
+        return;
+    }
+    for highlight in change_highlights.highlights.iter_mut() {
+        highlight.remaining -= time.delta_secs();
+        let alpha = (highlight.remaining / HIGHLIGHT_TIME).clamp(0.0, 1.0);
+        gizmos.linestrip_2d(highlight.points.iter().map(|point| Vec2::from(world_space.to_world(*point))), highlight.color.with_alpha(alpha));
+    }
+    change_highlights.highlights.retain(|h| h.remaining > 0.0);
+}
@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy_pancam::PanCam;
+
+use crate::map::{camera_scale_for_zoom, Local, MapBundle, Projection, Reprojection, Utm, WebMercator, WorldPos, WorldSpace, MAX_ZOOM};
+
+use super::{spawn_region_background, FlyTo, HistoricalView, RegionBackground};
+
+/// The camera along with everything about it which depends on the projection.
+type CameraToReproject<'a> = (Entity, &'a mut Transform, &'a mut OrthographicProjection, &'a mut PanCam, Option<&'a FlyTo>);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProjectionKind {
+    #[default]
+    WebMercator,
+    Utm,        // The zone is picked from where the camera is when it is switched to
+    Local,      // Centred on where the camera is when it is switched to
+}
+
+impl ProjectionKind {
+    pub const ALL: [ProjectionKind; 3] = [ProjectionKind::WebMercator, ProjectionKind::Utm, ProjectionKind::Local];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ProjectionKind::WebMercator => "Web Mercator",
+            ProjectionKind::Utm => "UTM",
+            ProjectionKind::Local => "Local",
+        }
+    }
+}
+
+/// Which projection the map is drawn with, changing `kind` reprojects everything that is loaded.
+#[derive(Resource, Default)]
+pub struct ProjectionSettings {
+    pub kind: ProjectionKind,
+    applied: ProjectionKind,
+}
+
+/// Switches the projection when it has been changed in the settings. The features and what has been loaded are kept
+/// in lat and long so they stay as they are, only what is drawn has to be redrawn with the new projection.
+/// The camera stays looking at the same place at the same scale on the ground.
+pub fn reproject_map(
+    mut commands: Commands,
+    mut settings: ResMut<ProjectionSettings>,
+    mut camera: Query<CameraToReproject, With<Camera2d>>,
+    backgrounds: Query<(Entity, &RegionBackground)>,
+    mut map_bundle: ResMut<MapBundle>,
+    mut history: ResMut<HistoricalView>,
+    mut world_space: ResMut<WorldSpace>,
+) {
+    if settings.kind == settings.applied {
+        return;
+    }
+    settings.applied = settings.kind;
+    let Ok((entity, mut transform, mut ortho, mut pancam, fly_to)) = camera.get_single_mut() else { return };
+
+    let from = world_space.clone();
+    let center = from.to_lon_lat(WorldPos::from(transform.translation.truncate()));
+    let projection: Arc<dyn Projection> = match settings.kind {
+        ProjectionKind::WebMercator => Arc::new(WebMercator),
+        ProjectionKind::Utm => Arc::new(Utm::for_point(center)),
+        ProjectionKind::Local => Arc::new(Local { centre: center }),
+    };
+    info!("Switching to the {} projection", projection.name());
+    *world_space = from.with_projection(projection);
+    let reprojection = Reprojection { from, to: world_space.clone() };
+
+    // Zoom so a pixel covers the same amount of ground as it did before
+    let stretch = (reprojection.to.projection.scale_factor(center) / reprojection.from.projection.scale_factor(center)) as f32;
+    let position = reprojection.point(transform.translation.truncate());
+    transform.translation = position.extend(transform.translation.z);
+    ortho.scale *= stretch;
+    pancam.min_scale = camera_scale_for_zoom(MAX_ZOOM, center, &world_space);
+    if let Some(fly_to) = fly_to {
+        // Start the flight again from here, the old one was going to where the target used to be
+        commands.entity(entity).insert(FlyTo::new(reprojection.point(fly_to.target), fly_to.scale * stretch));
+    }
+
+    for (entity, background) in backgrounds.iter() {
+        commands.entity(entity).despawn_recursive();
+        spawn_region_background(&mut commands, &world_space, background.0);
+    }
+
+    map_bundle.redraw = true;
+    history.respawn = true;
+}
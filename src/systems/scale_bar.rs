@@ -0,0 +1,37 @@
+use bevy::prelude::*;
+use bevy_egui::{egui::{self, Color32, RichText, Stroke}, EguiContexts};
+
+use crate::map::{format_distance, metres_per_pixel, scale_bar_length, WorldSpace};
+
+use super::{CameraSettings, OccupiedScreenSpace};
+
+/// The longest the scale bar gets, in pixels.
+const SCALE_BAR_WIDTH: f64 = 120.0;
+
+/// Draws a scale bar above the attribution, along with the zoom level.
+/// The distance is measured at the middle of the window, most projections make it change as you move around.
+pub fn draw_scale_bar(
+    mut contexts: EguiContexts,
+    occupied_screen_space: Res<OccupiedScreenSpace>,
+    camera_settings: Res<CameraSettings>,
+    camera: Query<&Transform, With<Camera2d>>,
+    world_space: Res<WorldSpace>,
+) {
+    let Ok(transform) = camera.get_single() else { return };
+    let center = world_space.to_lon_lat(transform.translation.truncate().into());
+    let (metres, pixels) = scale_bar_length(metres_per_pixel(camera_settings.scale, center, &world_space), SCALE_BAR_WIDTH);
+
+    let color = Color32::from_rgb(200, 200, 200);
+    egui::Area::new(egui::Id::new("scale_bar"))
+        .anchor(egui::Align2::LEFT_BOTTOM, [occupied_screen_space.left + 6.0, -24.0])
+        .interactable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(RichText::new(format!("{}   z{:.1}", format_distance(metres), camera_settings.zoom)).small().color(color));
+            let (rect, _) = ui.allocate_exact_size(egui::vec2(pixels as f32, 6.0), egui::Sense::hover());
+            let stroke = Stroke::new(2.0, color);
+            // A line with a tick at each end
+            ui.painter().line_segment([rect.left_bottom(), rect.right_bottom()], stroke);
+            ui.painter().line_segment([rect.left_top(), rect.left_bottom()], stroke);
+            ui.painter().line_segment([rect.right_top(), rect.right_bottom()], stroke);
+        });
+}
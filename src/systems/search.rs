@@ -0,0 +1,117 @@
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_egui::{egui::{self, Color32, RichText}, EguiContexts};
+use crossbeam_channel::{bounded, Receiver};
+
+use crate::{map::{LonLat, LonLatRect, WorldSpace, WorldSpaceRect}, webapi::{search_places, Place, NOMINATIM_URL}};
+
+use super::FlyTo;
+
+/// Where place names and addresses are looked up, this can be any nominatim compatible server.
+#[derive(Resource)]
+pub struct GeocoderSettings {
+    pub endpoint: String,
+}
+
+impl Default for GeocoderSettings {
+    fn default() -> Self {
+        GeocoderSettings {
+            endpoint: std::env::var("OSM_VIEWER_GEOCODER").unwrap_or_else(|_| NOMINATIM_URL.to_string()),
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct PlaceSearch {
+    pub query: String,
+    pub results: Vec<Place>,
+    pub error: Option<String>,
+    pending: Option<Receiver<Result<Vec<Place>, String>>>,
+}
+
+impl PlaceSearch {
+    fn search(&mut self, endpoint: String) {
+        let query = self.query.trim().to_string();
+        if query.is_empty() {
+            return;
+        }
+        let (tx, rx) = bounded(1);
+        std::thread::spawn(move || {
+            let _ = tx.send(search_places(&endpoint, &query).map_err(|e| e.to_string()));
+        });
+        self.pending = Some(rx);
+        self.error = None;
+    }
+
+    fn poll(&mut self) {
+        let Some(rx) = &self.pending else { return };
+        if let Ok(result) = rx.try_recv() {
+            match result {
+                Ok(results) => {
+                    if results.is_empty() {
+                        self.error = Some("Nothing found".to_string());
+                    }
+                    self.results = results;
+                }
+                Err(e) => self.error = Some(e),
+            }
+            self.pending = None;
+        }
+    }
+}
+
+/// The area the camera should show for a place, places without a bounding box get a small area around them.
+fn place_rect(place: &Place, world_space: &WorldSpace) -> WorldSpaceRect {
+    let bounds = place.bounds.unwrap_or_else(|| {
+        let LonLat { lon, lat } = place.position;
+        LonLatRect::from_corners(LonLat::new(lon - 0.003, lat - 0.002), LonLat::new(lon + 0.003, lat + 0.002))
+    });
+    world_space.rect_to_world(bounds)
+}
+
+pub fn place_search_panel(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut place_search: ResMut<PlaceSearch>,
+    mut geocoder_settings: ResMut<GeocoderSettings>,
+    camera: Query<Entity, With<Camera2d>>,
+    primary_window_query: Query<&Window, With<PrimaryWindow>>,
+    world_space: Res<WorldSpace>,
+) {
+    place_search.poll();
+
+    let mut chosen = None;
+    egui::Window::new("Search")
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 8.0])
+        .default_open(false)
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                let response = ui.text_edit_singleline(&mut place_search.query);
+                let entered = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if (ui.button("Go").clicked() || entered) && place_search.pending.is_none() {
+                    let endpoint = geocoder_settings.endpoint.clone();
+                    place_search.search(endpoint);
+                }
+            });
+            if place_search.pending.is_some() {
+                ui.spinner();
+            }
+            if let Some(error) = &place_search.error {
+                ui.label(RichText::new(error).color(Color32::from_rgb(230, 90, 90)));
+            }
+            for (i, place) in place_search.results.iter().enumerate() {
+                if ui.selectable_label(false, &place.name).clicked() {
+                    chosen = Some(i);
+                }
+            }
+            ui.collapsing("Geocoder", |ui| {
+                ui.text_edit_singleline(&mut geocoder_settings.endpoint);
+            });
+        });
+
+    if let Some(place) = chosen.and_then(|i| place_search.results.get(i)) {
+        info!("Flying to {}", place.name);
+        let window = primary_window_query.single();
+        commands.entity(camera.single()).insert(FlyTo::to_rect(&place_rect(place, &world_space), window, &world_space));
+    }
+}
@@ -1,11 +1,8 @@
 use bevy::prelude::*;
 use bevy_egui::{egui::{self, color_picker::color_edit_button_srgba, Color32, RichText}, EguiContexts};
-use bevy_prototype_lyon::entity::Path;
-use crate::{map::MapBundle, systems::settings::egui::color_picker::Alpha::Opaque};
+use crate::{map::{zoom_level, MapBundle, WorldSpace, STARTING_LON_LAT}, systems::settings::egui::color_picker::Alpha::Opaque};
 
-use crate::map::MapFeature;
-
-use super::{overpass_types::SettingsOverlay, CameraSettings};
+use super::{overpass_types::SettingsOverlay, AreaLoader, BatchedRendering, CameraSettings, HistoricalView, ProjectionKind, ProjectionSettings, ProvenanceSettings};
 
 
 pub struct SettingsPlugin;
@@ -15,7 +12,7 @@ impl Plugin for SettingsPlugin {
         app.add_systems(Update, ui_example_system)
             .init_resource::<OccupiedScreenSpace>()
             .insert_resource(SettingsOverlay::new())
-            .insert_resource(CameraSettings { scale: 1.0 });
+            .insert_resource(CameraSettings { scale: 1.0, zoom: zoom_level(1.0, STARTING_LON_LAT, &WorldSpace::default()) });
     }
 }
 
@@ -27,13 +24,18 @@ pub struct OccupiedScreenSpace {
     bottom: f32,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn ui_example_system(
     mut contexts: EguiContexts,
     mut occupied_screen_space: ResMut<OccupiedScreenSpace>,
     mut overpass_settings: ResMut<SettingsOverlay>,
-    shapes_query: Query<(Entity, &Path, &GlobalTransform, &MapFeature)>,
     mut map_bundle: ResMut<MapBundle>,
-    mut commands: Commands
+    mut provenance_settings: ResMut<ProvenanceSettings>,
+    mut history: ResMut<HistoricalView>,
+    mut area_loader: ResMut<AreaLoader>,
+    mut projection_settings: ResMut<ProjectionSettings>,
+    mut batching: ResMut<BatchedRendering>,
+    world_space: Res<WorldSpace>,
 ) {
     let ctx = contexts.ctx_mut();
 
@@ -91,18 +93,38 @@ fn ui_example_system(
                                     map_bundle.get_more_data = true;
                                 }
                                 if color_edit_button_srgba(ui, clr, Opaque).changed() {
-                                    // TODO: Find a way to not update as soon as it changes but only when the user is done.
-                                    map_bundle.respawn = true;
+                                    // Only the colors change, so the entities are restyled rather than respawned
+                                    let layer = (category_name.clone(), item_name.clone());
+                                    if !map_bundle.restyle.contains(&layer) {
+                                        map_bundle.restyle.push(layer);
+                                    }
                                 }
                             });
                         }
                     });
                 }
-                if ui.button("Clear Map").on_hover_text("Despawns the data which makes up this map").clicked() {
-                    for (entity, _, _, _) in shapes_query.iter() {
-                        commands.entity(entity).despawn_recursive();
-                    } 
+                if ui.button("Clear Map").on_hover_text("Unloads the data which makes up this map, it is fetched again when you press U").clicked() {
+                    map_bundle.clear = true;
+                }
+                if ui.button("Data sources").on_hover_text("Shows where the loaded data came from and how old it is").clicked() {
+                    provenance_settings.open = !provenance_settings.open;
+                }
+                if ui.button("History").on_hover_text("Compares the map with how it was on an earlier date").clicked() {
+                    history.open = !history.open;
                 }
+                if ui.button("Load area").on_hover_text("Loads the whole of a city or district").clicked() {
+                    area_loader.open = !area_loader.open;
+                }
+                if ui.checkbox(&mut batching.enabled, "Merge shapes").on_hover_text("Draws each layer as one shape per tile, which is much faster when a lot is loaded").changed() {
+                    map_bundle.redraw = true;
+                }
+                egui::ComboBox::from_label("Projection")
+                    .selected_text(world_space.projection.name())
+                    .show_ui(ui, |ui| {
+                        for kind in ProjectionKind::ALL {
+                            ui.selectable_value(&mut projection_settings.kind, kind, kind.label());
+                        }
+                    });
             });
             
     
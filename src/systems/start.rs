@@ -0,0 +1,149 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use crossbeam_channel::{bounded, Receiver, TryRecvError};
+
+use crate::{map::{LonLat, MapBundle, WorldSpace}, webapi::{locate_start, ConfiguredHome, Gpsd, IpGeolocation, LastSession, LocationProvider, GPSD_ADDRESS, IP_GEOLOCATION_URL}};
+
+use super::{set_world_origin, InWorldSpace};
+
+/// How often the camera position is saved for the next session, in seconds.
+const SESSION_SAVE_INTERVAL: f32 = 5.0;
+
+/// Where the map starts, the providers are tried in order and Cambridge is used if none of them know.
+///
+/// This is set up from the environment:
+/// - `OSM_VIEWER_START` is the order to try them in, out of `home`, `session`, `ip` and `gpsd`. The default is `home,session`
+/// - `OSM_VIEWER_HOME` is the home location as `lat,lon`
+/// - `OSM_VIEWER_SESSION` is the file the last position is kept in
+/// - `OSM_VIEWER_GEOLOCATION` is the IP geolocation endpoint
+/// - `OSM_VIEWER_GPSD` is the address of the gpsd daemon
+#[derive(Resource)]
+pub struct StartLocation {
+    pub providers: Vec<Box<dyn LocationProvider>>,
+    pub session: LastSession,           // Saved to whether or not it is one of the providers
+    pub chosen: Option<String>,         // The name of the provider the map started from
+    pending: Option<Receiver<Option<(LonLat, String)>>>,   // The providers are being tried in the background
+}
+
+impl Default for StartLocation {
+    fn default() -> Self {
+        let session_path = std::env::var("OSM_VIEWER_SESSION").unwrap_or_else(|_| "last_session.json".to_string());
+        let order = std::env::var("OSM_VIEWER_START").unwrap_or_else(|_| "home,session".to_string());
+
+        let mut providers: Vec<Box<dyn LocationProvider>> = Vec::new();
+        for name in order.split(',').map(str::trim) {
+            match name {
+                "home" => {
+                    if let Some(home) = std::env::var("OSM_VIEWER_HOME").ok().and_then(|home| parse_lat_lon(&home)) {
+                        providers.push(Box::new(ConfiguredHome(home)));
+                    }
+                }
+                "session" => providers.push(Box::new(LastSession { path: PathBuf::from(&session_path) })),
+                "ip" => providers.push(Box::new(IpGeolocation {
+                    endpoint: std::env::var("OSM_VIEWER_GEOLOCATION").unwrap_or_else(|_| IP_GEOLOCATION_URL.to_string()),
+                })),
+                "gpsd" => providers.push(Box::new(Gpsd {
+                    address: std::env::var("OSM_VIEWER_GPSD").unwrap_or_else(|_| GPSD_ADDRESS.to_string()),
+                })),
+                "" => {}
+                _ => warn!("Unknown start location provider {}", name),
+            }
+        }
+
+        StartLocation {
+            providers,
+            session: LastSession { path: PathBuf::from(session_path) },
+            chosen: None,
+            pending: None,
+        }
+    }
+}
+
+impl StartLocation {
+    /// Whether the providers are still being tried, nothing is loaded until they are done so the first fetch is for the right place.
+    pub fn is_locating(&self) -> bool {
+        self.pending.is_some()
+    }
+}
+
+/// Reads `52.2053,0.1218`, the same way round as most maps show it.
+fn parse_lat_lon(input: &str) -> Option<LonLat> {
+    let (lat, lon) = input.split_once(',')?;
+    Some(LonLat::new(lon.trim().parse().ok()?, lat.trim().parse().ok()?))
+}
+
+/// Starts trying the providers on a thread of their own, some of them go over the network and would hold up the window opening.
+pub fn choose_start_location(mut start_location: ResMut<StartLocation>) {
+    if start_location.providers.is_empty() {
+        return;
+    }
+    let providers = std::mem::take(&mut start_location.providers);
+    let (tx, rx) = bounded(1);
+    std::thread::spawn(move || {
+        let _ = tx.send(locate_start(&providers));
+    });
+    start_location.pending = Some(rx);
+}
+
+/// Moves the camera to the start location once one of the providers knows it, then loads what is around it.
+/// The world origin is moved there first, so the camera starts at 0, 0 wherever on earth that is.
+pub fn move_to_start_location(
+    mut start_location: ResMut<StartLocation>,
+    mut camera: Query<&mut Transform, With<Camera2d>>,
+    mut entities: Query<&mut Transform, InWorldSpace>,
+    mut map_bundle: ResMut<MapBundle>,
+    mut world_space: ResMut<WorldSpace>,
+) {
+    let Some(pending) = &start_location.pending else { return };
+    let found = match pending.try_recv() {
+        Ok(found) => found,
+        Err(TryRecvError::Empty) => return,
+        Err(TryRecvError::Disconnected) => None,
+    };
+    start_location.pending = None;
+
+    if let Some((position, provider)) = found {
+        info!("Starting at {:.5}, {:.5} from {}", position.lat, position.lon, provider);
+        set_world_origin(&mut world_space, position, &mut entities);
+        if let Ok(mut transform) = camera.get_single_mut() {
+            transform.translation = Vec3::new(0.0, 0.0, transform.translation.z);
+        }
+        start_location.chosen = Some(provider);
+    }
+    map_bundle.get_more_data = true;
+    map_bundle.respawn = true;
+}
+
+/// Keeps the last session up to date with where the camera is, so the next run can start there.
+pub fn save_session(
+    time: Res<Time>,
+    start_location: Res<StartLocation>,
+    camera: Query<&Transform, With<Camera2d>>,
+    mut exit: EventReader<AppExit>,
+    mut since_saved: Local<f32>,
+    mut last_saved: Local<Option<Vec2>>,
+    world_space: Res<WorldSpace>,
+) {
+    *since_saved += time.delta_secs();
+    let exiting = exit.read().next().is_some();
+    // The camera hasn't got to where it is starting from yet, saving now would lose the last session
+    if start_location.is_locating() {
+        return;
+    }
+    if *since_saved < SESSION_SAVE_INTERVAL && !exiting {
+        return;
+    }
+    *since_saved = 0.0;
+
+    let Ok(transform) = camera.get_single() else { return };
+    let position = transform.translation.truncate();
+    // The origin moving moves the camera as well, so this only saves more than it needs to
+    if *last_saved == Some(position) && !exiting {
+        return;
+    }
+    *last_saved = Some(position);
+    if let Err(e) = start_location.session.save(world_space.to_lon_lat(position.into())) {
+        info!("Couldn't save the session: {}", e);
+    }
+}
@@ -0,0 +1,78 @@
+use std::{fs, path::PathBuf, time::SystemTime};
+
+use bevy::prelude::*;
+
+use crate::map::{MapBundle, Stylesheet};
+
+/// Where the stylesheet is read from when `OSM_VIEWER_STYLE` isn't set.
+const STYLE_PATH: &str = "assets/style.mapcss";
+/// The stylesheet that comes with the viewer, used until one has been read from the file.
+const DEFAULT_STYLE: &str = include_str!("../../assets/style.mapcss");
+/// How often the stylesheet is checked for changes, in seconds.
+const STYLE_CHECK_INTERVAL: f32 = 1.0;
+
+/// The stylesheet everything is drawn with, which is read again whenever the file changes.
+#[derive(Resource)]
+pub struct MapStyle {
+    pub stylesheet: Stylesheet,
+    path: PathBuf,
+    modified: Option<SystemTime>,   // When the file had last changed when it was read
+}
+
+impl Default for MapStyle {
+    fn default() -> Self {
+        let path = std::env::var("OSM_VIEWER_STYLE").unwrap_or_else(|_| STYLE_PATH.to_string());
+        let mut style = MapStyle {
+            stylesheet: Stylesheet::parse(DEFAULT_STYLE).expect("the default stylesheet should parse"),
+            path: PathBuf::from(path),
+            modified: None,
+        };
+        style.reload();
+        style
+    }
+}
+
+impl MapStyle {
+    /// Reads the stylesheet again if the file has changed since it was last read, gives back whether it was.
+    /// One that doesn't parse is logged and the last good one kept, so saving a half finished edit doesn't break the map.
+    fn reload(&mut self) -> bool {
+        let Ok(modified) = fs::metadata(&self.path).and_then(|metadata| metadata.modified()) else { return false };
+        if self.modified == Some(modified) {
+            return false;
+        }
+        self.modified = Some(modified);
+
+        let stylesheet = fs::read_to_string(&self.path)
+            .map_err(|e| e.into())
+            .and_then(|source| Stylesheet::parse(&source));
+        match stylesheet {
+            Ok(stylesheet) => {
+                info!("Loaded the map style from {}", self.path.display());
+                self.stylesheet = stylesheet;
+                true
+            }
+            Err(e) => {
+                error!("Couldn't load the map style from {}: {}", self.path.display(), e);
+                false
+            }
+        }
+    }
+}
+
+/// Redraws the map when the stylesheet has been changed, so it can be restyled without restarting.
+pub fn reload_map_style(
+    time: Res<Time>,
+    mut style: ResMut<MapStyle>,
+    mut map_bundle: ResMut<MapBundle>,
+    mut since_checked: Local<f32>,
+) {
+    *since_checked += time.delta_secs();
+    if *since_checked < STYLE_CHECK_INTERVAL {
+        return;
+    }
+    *since_checked = 0.0;
+
+    if style.reload() {
+        map_bundle.redraw = true;
+    }
+}
@@ -0,0 +1,220 @@
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+use bevy::{prelude::*, render::{mesh::Indices, render_asset::RenderAssetUsages, render_resource::PrimitiveTopology}, sprite::AlphaMode2d, tasks::{block_on, poll_once, AsyncComputeTaskPool, Task}};
+use bevy_prototype_lyon::prelude::*;
+
+use crate::map::{detail_zoom, simplified, LonLat, MapFeature, WorldSpace, FULL_DETAIL};
+
+use super::FeatureStyle;
+
+/// How long a frame can spend putting finished meshes on to entities, the rest wait for the next frame.
+const SPAWN_BUDGET: Duration = Duration::from_millis(4);
+/// How many shapes are tessellated in one task, so there aren't thousands of tiny tasks.
+const SHAPES_PER_TASK: usize = 256;
+
+/// Something to tessellate, the mesh goes on to `entity` once it is done.
+pub struct ShapeJob {
+    pub entity: Entity,
+    pub features: Vec<(MapFeature, FeatureStyle)>,  // One for a feature with an entity of its own, or everything in a batch
+}
+
+pub struct TessellatedShape {
+    entity: Entity,
+    mesh: Mesh,
+    fill_vertices: usize,   // The fill comes before the stroke, so the colors can be changed without tessellating again
+    line_width: f32,
+    origin: LonLat,         // The world origin the mesh was made around
+}
+
+/// The size and colors of a feature's mesh, so it can be restyled in place.
+#[derive(Component, Default)]
+pub struct FeatureShape {
+    pub fill_vertices: usize,
+    pub line_width: f32,
+}
+
+/// Tessellates shapes on the async compute pool so data arriving doesn't make the frame hitch.
+/// Entities are spawned straight away with an empty mesh, which is filled in once it is ready.
+#[derive(Resource)]
+pub struct ShapeTessellator {
+    tasks: Vec<Task<Vec<TessellatedShape>>>,
+    ready: VecDeque<TessellatedShape>,
+    pub material: Handle<ColorMaterial>,    // White and blended, the colors are in the vertices
+    pub detail: usize,                      // The level of detail shapes are tessellated at, `camera_change` keeps this up to date
+    pub zoom: f64,                          // The zoom the narrowest roads are sized for, `camera_change` keeps it near the camera's
+}
+
+impl FromWorld for ShapeTessellator {
+    fn from_world(world: &mut World) -> Self {
+        // Blended, otherwise the shader throws away the alpha in the vertex colors and nothing can be see-through
+        let material = world.resource_mut::<Assets<ColorMaterial>>().add(ColorMaterial {
+            color: Color::WHITE,
+            alpha_mode: AlphaMode2d::Blend,
+            texture: None,
+        });
+        ShapeTessellator { tasks: Vec::new(), ready: VecDeque::new(), material, detail: FULL_DETAIL, zoom: detail_zoom(FULL_DETAIL) }
+    }
+}
+
+impl ShapeTessellator {
+    pub fn submit(&mut self, mut jobs: Vec<ShapeJob>, world_space: &WorldSpace) {
+        let pool = AsyncComputeTaskPool::get();
+        while !jobs.is_empty() {
+            let chunk: Vec<ShapeJob> = jobs.drain(..jobs.len().min(SHAPES_PER_TASK)).collect();
+            // The world space is taken now, it could move before the task gets to run
+            let world_space = world_space.clone();
+            let detail = self.detail;
+            self.tasks.push(pool.spawn(async move {
+                chunk.into_iter().map(|job| tessellate(job, &world_space, detail)).collect()
+            }));
+        }
+    }
+}
+
+/// The entity a mesh is for, with nothing drawn until the mesh is ready.
+pub fn shape_entity(tessellator: &ShapeTessellator, elevation: f32) -> impl Bundle {
+    (
+        Mesh2d::default(),
+        MeshMaterial2d(tessellator.material.clone()),
+        Transform::from_xyz(0.0, 0.0, elevation),
+    )
+}
+
+/// Builds one mesh out of all of the features in a job, the same way lyon would for each of them on their own.
+/// Features are simplified to the level of detail first, ones too small to see are left out.
+fn tessellate(job: ShapeJob, world_space: &WorldSpace, detail: usize) -> TessellatedShape {
+    let mut buffers: tess::VertexBuffers<([f32; 3], [f32; 4]), u32> = tess::VertexBuffers::new();
+    let mut fill_tess = tess::FillTessellator::new();
+    let mut stroke_tess = tess::StrokeTessellator::new();
+    let mut fill_vertices = 0;
+    for (feature, style) in &job.features {
+        let fill_color = style.fill.map(|fill| LinearRgba::from(fill).to_f32_array());
+        let stroke_color = LinearRgba::from(style.stroke).to_f32_array();
+        let mut stroke_options = StrokeOptions::default().with_line_width(style.line_width);
+        if style.fill.is_none() {
+            // Rounded ends and corners so roads meeting at a junction overlap without any gaps or spikes
+            stroke_options = stroke_options.with_line_cap(LineCap::Round).with_line_join(LineJoin::Round);
+        }
+
+        let Some(outline) = simplified(feature, detail) else { continue };
+        let mut points: Vec<Vec2> = outline.exterior().coords().map(|c| world_space.to_world(LonLat::from(*c)).into()).collect();
+        points.pop();
+        let dashed = style.dashes.as_ref().map(|dashes| dash_line(&points, dashes));
+        let path = GeometryBuilder::build_as(&shapes::Polygon { points, closed: false });
+
+        if let Some(color) = fill_color {
+            let result = fill_tess.tessellate_path(&path.0, &FillOptions::default(), &mut tess::BuffersBuilder::new(&mut buffers, |v: tess::FillVertex| {
+                ([v.position().x, v.position().y, 0.0], color)
+            }));
+            if let Err(e) = result {
+                error!("Couldn't fill {}: {:?}", feature.id, e);
+            }
+            fill_vertices = buffers.vertices.len();
+        }
+        if style.line_width <= 0.0 {
+            continue;   // No stroke, which is what `color: none` gives
+        }
+        let strokes = match dashed {
+            Some(dashes) => dashes.into_iter().map(|points| GeometryBuilder::build_as(&shapes::Polygon { points, closed: false })).collect(),
+            None => vec![path],
+        };
+        for stroke in strokes {
+            let result = stroke_tess.tessellate_path(&stroke.0, &stroke_options, &mut tess::BuffersBuilder::new(&mut buffers, |v: tess::StrokeVertex| {
+                ([v.position().x, v.position().y, 0.0], stroke_color)
+            }));
+            if let Err(e) = result {
+                error!("Couldn't stroke {}: {:?}", feature.id, e);
+            }
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default());
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, buffers.vertices.iter().map(|v| v.0).collect::<Vec<_>>());
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, buffers.vertices.iter().map(|v| v.1).collect::<Vec<_>>());
+    mesh.insert_indices(Indices::U32(buffers.indices));
+
+    // The width is only used to tell if a feature needs tessellating again, which batches never are
+    let line_width = job.features.first().map_or(0.0, |(_, style)| style.line_width);
+    TessellatedShape { entity: job.entity, mesh, fill_vertices, line_width, origin: world_space.origin }
+}
+
+/// Cuts a line into dashes, `dashes` goes dash, gap, dash, gap and so on in world space, repeating once it gets to the end.
+fn dash_line(points: &[Vec2], dashes: &[f32]) -> Vec<Vec<Vec2>> {
+    if dashes.iter().sum::<f32>() <= 0.0 || dashes.iter().any(|dash| *dash < 0.0) {
+        return vec![points.to_vec()];
+    }
+    // An odd number of lengths is gone through twice, so the dashes and gaps swap over the second time
+    let dashes = if dashes.len() % 2 == 1 { dashes.repeat(2) } else { dashes.to_vec() };
+    let mut pieces = Vec::new();
+    let mut current: Vec<Vec2> = Vec::new();
+    let (mut index, mut left) = (0, dashes[0]);
+    for segment in points.windows(2) {
+        let (mut from, to) = (segment[0], segment[1]);
+        loop {
+            let drawing = index % 2 == 0;
+            if drawing && current.is_empty() {
+                current.push(from);
+            }
+            let length = from.distance(to);
+            if length <= left {
+                left -= length;
+                if drawing {
+                    current.push(to);
+                }
+                break;
+            }
+            from = from.lerp(to, left / length);
+            if drawing {
+                current.push(from);
+                pieces.push(std::mem::take(&mut current));
+            }
+            index = (index + 1) % dashes.len();
+            left = dashes[index];
+        }
+    }
+    if current.len() > 1 {
+        pieces.push(current);
+    }
+    pieces
+}
+
+/// Sets the colors of a feature's mesh without tessellating it again.
+pub fn recolor_mesh(mesh: &mut Mesh, fill_vertices: usize, style: &FeatureStyle) {
+    let fill = style.fill.map_or([0.0; 4], |fill| LinearRgba::from(fill).to_f32_array());
+    let stroke = LinearRgba::from(style.stroke).to_f32_array();
+    let count = mesh.count_vertices();
+    let colors: Vec<[f32; 4]> = (0..count).map(|i| if i < fill_vertices { fill } else { stroke }).collect();
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+}
+
+/// Picks up finished meshes and puts them on to their entities, stopping once the frame's budget is used up.
+/// Entities which have been despawned in the meantime are skipped.
+pub fn spawn_tessellated_shapes(
+    mut tessellator: ResMut<ShapeTessellator>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut shapes: Query<(&mut Mesh2d, &mut Transform, Option<&mut FeatureShape>)>,
+    world_space: Res<WorldSpace>,
+) {
+    let ShapeTessellator { tasks, ready, .. } = &mut *tessellator;
+    tasks.retain_mut(|task| match block_on(poll_once(task)) {
+        Some(shapes) => {
+            ready.extend(shapes);
+            false
+        }
+        None => true,
+    });
+
+    let started = Instant::now();
+    while started.elapsed() < SPAWN_BUDGET {
+        let Some(shape) = ready.pop_front() else { break };
+        let Ok((mut mesh, mut transform, feature_shape)) = shapes.get_mut(shape.entity) else { continue };
+        mesh.0 = meshes.add(shape.mesh);
+        // The origin may have moved since, this puts the mesh where its origin is now
+        let offset = Vec2::from(world_space.to_world(shape.origin));
+        transform.translation = offset.extend(transform.translation.z);
+        if let Some(mut feature_shape) = feature_shape {
+            feature_shape.fill_vertices = shape.fill_vertices;
+            feature_shape.line_width = shape.line_width;
+        }
+    }
+}
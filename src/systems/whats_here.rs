@@ -0,0 +1,167 @@
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_egui::{egui::{self, Color32, RichText}, EguiContexts};
+use crossbeam_channel::{bounded, Receiver};
+use geo::Contains;
+use rstar::{Envelope, RTree, RTreeObject, AABB};
+
+use crate::{map::{LonLat, MapBundle, MapFeature, WorldSpace}, webapi::reverse_geocode};
+
+use super::GeocoderSettings;
+
+/// How far from the click loaded addresses are looked for, in degrees. This is roughly 50m.
+const ADDRESS_SEARCH_RADIUS: f64 = 0.0005;
+
+/// The state of the right click "What's here?" menu and its answer.
+#[derive(Resource, Default)]
+pub struct WhatsHere {
+    menu: Option<(egui::Pos2, LonLat)>,     // Where the menu is on screen, and the point that was clicked
+    pub result: Option<WhatsHereResult>,
+    pending: Option<Receiver<WhatsHereResult>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct WhatsHereResult {
+    pub position: LonLat,
+    pub address: Option<String>,
+    pub source: &'static str,
+}
+
+/// Builds an address like `12 Mill Road, Cambridge CB1 2AB` from the `addr:*` tags, none if there isn't one.
+pub fn format_address(properties: &serde_json::Value) -> Option<String> {
+    let tag = |key: &str| properties.get(key).and_then(|v| v.as_str()).filter(|v| !v.is_empty());
+    let street = tag("addr:street").or_else(|| tag("addr:place"));
+    let number = tag("addr:housenumber");
+    let house_name = tag("addr:housename");
+    if street.is_none() && number.is_none() && house_name.is_none() {
+        return None;
+    }
+
+    let first_line = [number, street].into_iter().flatten().collect::<Vec<_>>().join(" ");
+    let last_line = [tag("addr:city"), tag("addr:postcode")].into_iter().flatten().collect::<Vec<_>>().join(" ");
+    let parts: Vec<&str> = [house_name, Some(first_line.as_str()), Some(last_line.as_str())]
+        .into_iter()
+        .flatten()
+        .filter(|p| !p.is_empty())
+        .collect();
+    Some(parts.join(", "))
+}
+
+/// Finds the closest loaded feature with an address, a feature the point is inside of always wins.
+fn nearest_address(features: &RTree<MapFeature>, position: LonLat) -> Option<String> {
+    let LonLat { lon, lat } = position;
+    let search = AABB::from_corners(
+        [lon - ADDRESS_SEARCH_RADIUS, lat - ADDRESS_SEARCH_RADIUS],
+        [lon + ADDRESS_SEARCH_RADIUS, lat + ADDRESS_SEARCH_RADIUS],
+    );
+    let point = geo::Point::from(geo::Coord::from(position));
+    // Longitude degrees get shorter away from the equator
+    let lon_scale = lat.to_radians().cos();
+
+    features
+        .locate_in_envelope_intersecting(&search)
+        .filter_map(|feature| {
+            let address = format_address(&feature.properties)?;
+            let distance = if feature.geometry.contains(&point) {
+                0.0
+            } else {
+                let center = feature.envelope().center();
+                (((center[0] - lon) * lon_scale).powi(2) + (center[1] - lat).powi(2)).sqrt()
+            };
+            Some((distance, address))
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, address)| address)
+}
+
+/// Right clicking the map opens a "What's here?" menu which shows the nearest address and the coordinates.
+/// Addresses from the loaded data are used first, falling back to the geocoder's reverse lookup.
+#[allow(clippy::too_many_arguments)]
+pub fn whats_here(
+    mut contexts: EguiContexts,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), With<Camera2d>>,
+    mouse_button: Res<ButtonInput<MouseButton>>,
+    map_bundle: Res<MapBundle>,
+    geocoder_settings: Res<GeocoderSettings>,
+    mut whats_here: ResMut<WhatsHere>,
+    world_space: Res<WorldSpace>,
+) {
+    let ctx = contexts.ctx_mut();
+
+    if mouse_button.just_pressed(MouseButton::Right) && !ctx.is_pointer_over_area() {
+        let (camera, camera_transform) = camera.single();
+        if let Some(cursor_pos) = windows.single().cursor_position() {
+            if let Ok(world_position) = camera.viewport_to_world_2d(camera_transform, cursor_pos) {
+                let position = world_space.to_lon_lat(world_position.into());
+                whats_here.menu = Some((egui::pos2(cursor_pos.x, cursor_pos.y), position));
+            }
+        }
+    }
+
+    if let Some((pos, position)) = whats_here.menu {
+        let mut clicked = false;
+        let response = egui::Area::new(egui::Id::new("whats_here_menu"))
+            .fixed_pos(pos)
+            .order(egui::Order::Foreground)
+            .show(ctx, |ui| {
+                egui::Frame::menu(ui.style()).show(ui, |ui| {
+                    clicked = ui.button("What's here?").clicked();
+                });
+            })
+            .response;
+        if clicked {
+            whats_here.menu = None;
+            match nearest_address(&map_bundle.features, position) {
+                Some(address) => {
+                    whats_here.pending = None;
+                    whats_here.result = Some(WhatsHereResult { position, address: Some(address), source: "loaded map data" });
+                }
+                None => {
+                    let (tx, rx) = bounded(1);
+                    let endpoint = geocoder_settings.endpoint.clone();
+                    std::thread::spawn(move || {
+                        let address = match reverse_geocode(&endpoint, position) {
+                            Ok(place) => place.map(|p| p.name),
+                            Err(e) => {
+                                info!("Reverse geocoding failed: {}", e);
+                                None
+                            }
+                        };
+                        let _ = tx.send(WhatsHereResult { position, address, source: "geocoder" });
+                    });
+                    whats_here.pending = Some(rx);
+                    whats_here.result = Some(WhatsHereResult { position, address: None, source: "looking up..." });
+                }
+            }
+        } else if mouse_button.just_pressed(MouseButton::Left) && !response.hovered() {
+            whats_here.menu = None;
+        }
+    }
+
+    if let Some(rx) = &whats_here.pending {
+        if let Ok(result) = rx.try_recv() {
+            whats_here.result = Some(result);
+            whats_here.pending = None;
+        }
+    }
+
+    let mut open = whats_here.result.is_some();
+    if let Some(result) = &whats_here.result {
+        egui::Window::new("What's here?")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                match &result.address {
+                    Some(address) => ui.label(RichText::new(address).strong()),
+                    None if whats_here.pending.is_some() => ui.spinner(),
+                    None => ui.label(RichText::new("No address found").color(Color32::from_rgb(180, 180, 180))),
+                };
+                ui.label(format!("{:.6}, {:.6}", result.position.lat, result.position.lon));
+                ui.label(RichText::new(format!("From {}", result.source)).small());
+            });
+    }
+    if !open {
+        whats_here.result = None;
+        whats_here.pending = None;
+    }
+}
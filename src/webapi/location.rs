@@ -1,35 +1,125 @@
-/*
+use std::{io::{BufRead, BufReader, Write}, net::{TcpStream, ToSocketAddrs}, path::PathBuf, time::{Duration, Instant}};
 
-#[derive(Component, Default)]
-pub struct Loc {
-    pub latitude: f64,
-    pub longitude: f64,
+use bevy::prelude::*;
+
+use crate::map::LonLat;
+
+pub const IP_GEOLOCATION_URL: &str = "http://ip-api.com/json";
+pub const GPSD_ADDRESS: &str = "127.0.0.1:2947";
+/// How long a provider gets before the next one is tried, the map waits on this before it loads anything.
+const LOCATE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Somewhere the map can find out where to start from.
+/// Providers are tried in order until one of them knows, giving back none if it doesn't have a location.
+pub trait LocationProvider: Send + Sync {
+    fn name(&self) -> &str;
+    fn locate(&self) -> Result<Option<LonLat>, Box<dyn std::error::Error>>;
+}
+
+/// A fixed place, such as where the user lives.
+pub struct ConfiguredHome(pub LonLat);
+
+impl LocationProvider for ConfiguredHome {
+    fn name(&self) -> &str {
+        "home"
+    }
+
+    fn locate(&self) -> Result<Option<LonLat>, Box<dyn std::error::Error>> {
+        Ok(Some(self.0))
+    }
+}
+
+/// Wherever the camera was when the app was last closed.
+pub struct LastSession {
+    pub path: PathBuf,
+}
+
+impl LastSession {
+    pub fn save(&self, position: LonLat) -> std::io::Result<()> {
+        let json = serde_json::json!({ "lat": position.lat, "lon": position.lon });
+        std::fs::write(&self.path, json.to_string())
+    }
+}
+
+impl LocationProvider for LastSession {
+    fn name(&self) -> &str {
+        "last session"
+    }
+
+    fn locate(&self) -> Result<Option<LonLat>, Box<dyn std::error::Error>> {
+        // Not having a session yet isn't an error, it is just the first run
+        let Ok(contents) = std::fs::read_to_string(&self.path) else {
+            return Ok(None);
+        };
+        let value: serde_json::Value = serde_json::from_str(&contents)?;
+        Ok(lon_lat_from_json(&value, "lat", "lon"))
+    }
 }
 
-impl Loc {
-    pub fn new() -> Loc {
-        let lat_and_long = get_loc().unwrap();
-        Loc {
-            latitude: lat_and_long.latitude,
-            longitude: lat_and_long.longitude,
+/// Looks up roughly where the user is from their IP address.
+/// This works with ip-api.com style responses as well as ones which use `latitude` and `longitude`.
+pub struct IpGeolocation {
+    pub endpoint: String,
+}
+
+impl LocationProvider for IpGeolocation {
+    fn name(&self) -> &str {
+        "IP geolocation"
+    }
+
+    fn locate(&self) -> Result<Option<LonLat>, Box<dyn std::error::Error>> {
+        let response = ureq::get(&self.endpoint).timeout(LOCATE_TIMEOUT).call()?;
+        let value: serde_json::Value = serde_json::from_reader(response.into_reader())?;
+        Ok(lon_lat_from_json(&value, "lat", "lon").or_else(|| lon_lat_from_json(&value, "latitude", "longitude")))
+    }
+}
+
+/// Asks a gpsd style daemon for a fix, the address is a host and port such as `127.0.0.1:2947`.
+pub struct Gpsd {
+    pub address: String,
+}
+
+impl LocationProvider for Gpsd {
+    fn name(&self) -> &str {
+        "gpsd"
+    }
+
+    fn locate(&self) -> Result<Option<LonLat>, Box<dyn std::error::Error>> {
+        let address = self.address.to_socket_addrs()?.next().ok_or("gpsd address didn't resolve")?;
+        let mut stream = TcpStream::connect_timeout(&address, LOCATE_TIMEOUT)?;
+        stream.set_read_timeout(Some(LOCATE_TIMEOUT))?;
+        stream.write_all(br#"?WATCH={"enable":true,"json":true};"#)?;
+
+        // The daemon says hello and lists its devices first, then sends position reports as they come in
+        let started = Instant::now();
+        for line in BufReader::new(stream).lines() {
+            if started.elapsed() > LOCATE_TIMEOUT {
+                break;
+            }
+            let report: serde_json::Value = serde_json::from_str(&line?)?;
+            // Mode 2 and 3 are 2D and 3D fixes, anything less has no position yet
+            let has_fix = report.get("class").and_then(|c| c.as_str()) == Some("TPV")
+                && report.get("mode").and_then(|m| m.as_i64()).unwrap_or_default() >= 2;
+            if has_fix {
+                return Ok(lon_lat_from_json(&report, "lat", "lon"));
+            }
         }
+        Ok(None)
     }
-    
-}
-
-fn get_loc() -> Result<Loc, ureq::Error> {
-    let response: String = ureq::get("http://ip-api.com/json")
-        .call()?
-        .into_string()?;
-    
-    let json: serde_json::Value = serde_json::from_str(&response).unwrap();
-    
-    let latitude = json["lat"].as_f64().unwrap_or(0.0);
-    let longitude = json["lon"].as_f64().unwrap_or(0.0);
-    
-    Ok(Loc {
-        latitude,
-        longitude,
-    })
-}
-    */
\ No newline at end of file
+}
+
+fn lon_lat_from_json(value: &serde_json::Value, lat: &str, lon: &str) -> Option<LonLat> {
+    Some(LonLat::new(value.get(lon)?.as_f64()?, value.get(lat)?.as_f64()?))
+}
+
+/// Goes through the providers in order and gives back the first location found, along with the name of the provider.
+pub fn locate_start(providers: &[Box<dyn LocationProvider>]) -> Option<(LonLat, String)> {
+    for provider in providers {
+        match provider.locate() {
+            Ok(Some(position)) => return Some((position, provider.name().to_string())),
+            Ok(None) => {}
+            Err(e) => info!("Couldn't get a location from {}: {}", provider.name(), e),
+        }
+    }
+    None
+}
@@ -1,4 +1,9 @@
 mod location;
 mod overpass;
+mod nominatim;
+mod progress;
 
+pub use location::*;
 pub use overpass::*;
+pub use nominatim::*;
+pub use progress::*;
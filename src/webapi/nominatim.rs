@@ -0,0 +1,72 @@
+use serde::Deserialize;
+
+use crate::map::{LonLat, LonLatRect};
+
+pub const NOMINATIM_URL: &str = "https://nominatim.openstreetmap.org";
+const USER_AGENT: &str = concat!("bevy-osm-viewer/", env!("CARGO_PKG_VERSION"));
+
+/// A place found by the geocoder.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Place {
+    pub name: String,
+    pub position: LonLat,
+    pub bounds: Option<LonLatRect>,
+}
+
+// Nominatim sends the numbers as strings
+#[derive(Deserialize)]
+struct NominatimPlace {
+    display_name: String,
+    lat: String,
+    lon: String,
+    #[serde(default)]
+    boundingbox: Vec<String>, // south, north, west, east
+}
+
+impl NominatimPlace {
+    fn into_place(self) -> Option<Place> {
+        let bounds = match self.boundingbox.iter().map(|v| v.parse::<f64>().ok()).collect::<Option<Vec<_>>>().as_deref() {
+            Some([min_lat, max_lat, min_lon, max_lon]) => Some(LonLatRect::from_corners(LonLat::new(*min_lon, *min_lat), LonLat::new(*max_lon, *max_lat))),
+            _ => None,
+        };
+        Some(Place {
+            name: self.display_name,
+            position: LonLat::new(self.lon.parse().ok()?, self.lat.parse().ok()?),
+            bounds,
+        })
+    }
+}
+
+/// Searches a nominatim compatible geocoder, `endpoint` is the base url without the `/search`.
+pub fn search_places(endpoint: &str, query: &str) -> Result<Vec<Place>, Box<dyn std::error::Error>> {
+    let url = format!("{}/search", endpoint.trim_end_matches('/'));
+    let response = ureq::get(&url)
+        // Nominatim asks that every app says who it is
+        .set("User-Agent", USER_AGENT)
+        .query("q", query)
+        .query("format", "jsonv2")
+        .query("limit", "10")
+        .call()?;
+    let places: Vec<NominatimPlace> = serde_json::from_reader(response.into_reader())?;
+
+    Ok(places.into_iter().filter_map(NominatimPlace::into_place).collect())
+}
+
+/// Looks up the address at a point with a nominatim compatible geocoder, none if there is nothing there.
+pub fn reverse_geocode(endpoint: &str, point: LonLat) -> Result<Option<Place>, Box<dyn std::error::Error>> {
+    let url = format!("{}/reverse", endpoint.trim_end_matches('/'));
+    let response = ureq::get(&url)
+        .set("User-Agent", USER_AGENT)
+        .query("lat", &point.lat.to_string())
+        .query("lon", &point.lon.to_string())
+        .query("format", "jsonv2")
+        .call()?;
+    let value: serde_json::Value = serde_json::from_reader(response.into_reader())?;
+    // Nothing being there comes back as an error object rather than an error status
+    if value.get("error").is_some() {
+        return Ok(None);
+    }
+    let place: NominatimPlace = serde_json::from_value(value)?;
+
+    Ok(place.into_place())
+}
@@ -1,31 +1,56 @@
-use std::io::{BufRead, BufReader, Read};
+use std::io::BufReader;
 
 use bevy::prelude::*;
-use geojson::{Geometry, Value};
+use crossbeam_channel::Sender;
 
-use crate::{map::{get_data_from_string_osm, MapBundle, MapFeature, WorldSpaceRect}, systems::SettingsOverlay};
+use crate::{map::{assemble_rings, get_diff_from_reader_osm, stream_data_from_reader_osm, AreaBoundary, AreaRef, DataSource, Layer, LayerRegion, LonLat, LonLatRect, MapFeature, OsmDiff, OverpassMeta, FEATURE_CHUNK_SIZE}, systems::SettingsOverlay};
 
-fn build_overpass_query(bounds: Vec<WorldSpaceRect>, overpass_settings: &mut SettingsOverlay) -> String {
+use super::{RequestId, RequestPhase, RequestProgress};
+
+pub const OVERPASS_URL: &str = "https://overpass-api.de/api/interpreter";
+// pub const OVERPASS_URL: &str = "http://localhost:12345/api/interpreter";
+
+/// What a worker thread sends back to the map while it is fetching.
+#[derive(Debug)]
+pub enum OverpassMessage {
+    Features(Vec<MapFeature>),
+    Source(DataSource),
+    Diff { bounds: LonLatRect, diff: OsmDiff },
+    Snapshot { date: String, features: Vec<MapFeature> },
+    Area { id: RequestId, boundary: AreaBoundary, layers: Vec<Layer> },   // Sent before the features of an area, the rings are empty if it wasn't found
+    Done(RequestId),        // Everything for the request has been sent
+}
+
+fn build_overpass_query(regions: Vec<LayerRegion>) -> String {
+    build_layer_query("[out:json];(", ");(._;>;);\nout body geom;", regions)
+}
+
+/// Builds a query for all the enabled categories in each of the bounds, wrapped in `opening` and `closing`.
+fn build_query(opening: &str, closing: &str, bounds: Vec<LonLatRect>, overpass_settings: &SettingsOverlay) -> String {
+    let layers = overpass_settings.get_true_keys_with_category();
+    build_layer_query(opening, closing, bounds.into_iter().map(|rect| LayerRegion::new(rect, layers.clone())).collect())
+}
+
+/// Builds a query for just the given layers in each of the bounds.
+fn build_layer_query(opening: &str, closing: &str, regions: Vec<LayerRegion>) -> String {
     let mut query = String::default();
-    let opening = "[out:json];(";
-    let closing = ");(._;>;);\nout body geom;";
 
-    for bound in bounds {
-        for (category, key) in overpass_settings.get_true_keys_with_category() {
+    for LayerRegion { rect: bound, layers, .. } in regions {
+        for (category, key) in layers {
             if key == "n/a" {
                 continue;
             } else if key == "*" {
                 query.push_str(&format!(r#"
                 (
-                way["{}"]({},{},{},{}); 
+                way["{}"]({}); 
                 );
-                "#, category.to_lowercase(), bound.bottom, bound.right, bound.top, bound.left));
+                "#, category.to_lowercase(), bound.overpass_bbox()));
             } else {
                 query.push_str(&format!(r#"
                 (
-                way["{}"="{}"]({},{},{},{}); 
+                way["{}"="{}"]({}); 
                 );
-                "#, category.to_lowercase(), key.to_lowercase(), bound.bottom, bound.right, bound.top, bound.left));
+                "#, category.to_lowercase(), key.to_lowercase(), bound.overpass_bbox()));
             }
         }
     }
@@ -39,86 +64,215 @@ fn build_overpass_query(bounds: Vec<WorldSpaceRect>, overpass_settings: &mut Set
     query
 }
 
-pub fn get_overpass_data<'a>(bounds: Vec<WorldSpaceRect>, map_bundle: &mut MapBundle, overpass_settings: &mut SettingsOverlay,
-) -> Vec<MapFeature>  {
-    if bounds.is_empty() {
-        return vec![];
+/// Requests the bounds from overpass, the features are sent down `sender` in chunks as they are parsed.
+/// Only the layers of each region are asked for.
+/// Once it has finished the provenance of each of the bounds is sent as well.
+pub fn get_overpass_data(regions: Vec<LayerRegion>, progress: &RequestProgress, sender: &Sender<OverpassMessage>) {
+    if regions.is_empty() {
+        return finish_request(progress, true, sender);
+    }
+    let mut bounds: Vec<LonLatRect> = Vec::new();
+    for region in &regions {
+        if !bounds.contains(&region.rect) {
+            bounds.push(region.rect);
+        }
     }
-    let query = build_overpass_query(bounds, overpass_settings);
-    if query != "ERR" {
-        return send_overpass_query(query, map_bundle)
+    let query = build_overpass_query(regions);
+    if query == "ERR" {
+        return finish_request(progress, true, sender);
     }
-    vec![]
+    let meta = send_overpass_query(query, progress, sender, OverpassMessage::Features);
+    if let Some(meta) = &meta {
+        for bound in bounds {
+            let _ = sender.send(OverpassMessage::Source(DataSource {
+                bounds: bound,
+                source: OVERPASS_URL.to_string(),
+                generator: meta.generator.clone(),
+                timestamp: meta.timestamp_osm_base.clone(),
+                copyright: meta.copyright.clone(),
+                fetched_at: std::time::SystemTime::now(),
+            }));
+        }
+    }
+    finish_request(progress, meta.is_some(), sender);
 }
 
-fn match_geometry(geom: &Geometry) {
-    match geom.value {
-        Value::Polygon(_) => println!("Matched a Polygon"),
-        Value::MultiPolygon(_) => println!("Matched a MultiPolygon"),
-        Value::GeometryCollection(ref gc) => {
-            println!("Matched a GeometryCollection");
-            // !!! GeometryCollections contain other Geometry types, and can
-            // nest — we deal with this by recursively processing each geometry
-            for geometry in gc {
-                match_geometry(geometry)
+/// Lets the map know a request is over, failed requests are left to be shown as failed.
+fn finish_request(progress: &RequestProgress, succeeded: bool, sender: &Sender<OverpassMessage>) {
+    if succeeded {
+        progress.set_phase(RequestPhase::Spawning);
+        let _ = sender.send(OverpassMessage::Done(progress.id()));
+    } else if !progress.is_cancelled() {
+        progress.set_phase(RequestPhase::Failed);
+    }
+}
+
+/// Asks overpass for everything which has changed in the bounds since `since`, the result is sent back as one diff.
+pub fn get_overpass_diff(bounds: LonLatRect, since: &str, overpass_settings: &SettingsOverlay, progress: &RequestProgress, sender: &Sender<OverpassMessage>) {
+    // Augmented diffs only come as xml, and geom gives us the coordinates of the ways without needing the nodes
+    let query = build_query(&format!("[adiff:\"{}\"];(", since), ");\nout geom;", vec![bounds], overpass_settings);
+    if query == "ERR" {
+        return finish_request(progress, true, sender);
+    }
+    info!("Sending diff query: {}", query);
+    let mut succeeded = false;
+    if let Some(response) = post_overpass_query(&query, progress) {
+        match get_diff_from_reader_osm(BufReader::new(progress.reader(response.into_reader()))) {
+            Ok(diff) => {
+                info!("Got {} changes since {}", diff.changes.len(), since);
+                succeeded = sender.send(OverpassMessage::Diff { bounds, diff }).is_ok();
+            }
+            Err(e) => info!("Error reading diff: {}", e),
+        }
+    }
+    finish_request(progress, succeeded, sender);
+}
+
+/// Posts a query to overpass, waiting and trying again while we are being rate limited.
+/// This gives up as soon as the request is cancelled.
+fn post_overpass_query(query: &str, progress: &RequestProgress) -> Option<ureq::Response> {
+    loop {
+        if progress.is_cancelled() {
+            return None;
+        }
+        progress.set_phase(RequestPhase::Waiting);
+        match ureq::post(OVERPASS_URL).send_string(query) {
+            Ok(response) if response.status() == 200 => return Some(response).filter(|_| !progress.is_cancelled()),
+            // ureq gives back error statuses as errors rather than responses
+            Err(ureq::Error::Status(429, _)) => {
+                info!("Rate limited, waiting 5 seconds");
+                progress.set_phase(RequestPhase::Queued);
+                if !progress.wait(std::time::Duration::from_secs(5)) {
+                    return None;
+                }
             }
+            Ok(_) | Err(_) => return None,
         }
-        // Point, LineString, and their Multi– counterparts
-        _ => println!("Matched some other geometry"),
     }
 }
 
-fn send_overpass_query(query: String, map_bundle: &mut MapBundle,
-) -> Vec<MapFeature> {
+/// Loads everything in the enabled categories inside of a whole named area, regardless of what is in view.
+/// The boundary of the area is sent first so it can be drawn and marked as loaded.
+pub fn get_overpass_area(area: AreaRef, overpass_settings: &SettingsOverlay, progress: &RequestProgress, sender: &Sender<OverpassMessage>) {
+    let area_statement = area.area_statement();
+    let name = match &area {
+        AreaRef::Name(name) => name.clone(),
+        AreaRef::Relation(id) => format!("relation {}", id),
+    };
+
+    // The thing the area was made from, pivot gives back the relation or way
+    let boundary_query = format!("[out:json];{}(rel(pivot.a);way(pivot.a););out geom;", area_statement);
+    info!("Sending area query: {}", boundary_query);
+    let boundary = post_overpass_query(&boundary_query, progress)
+        .and_then(|response| serde_json::from_reader::<_, serde_json::Value>(progress.reader(response.into_reader())).ok())
+        .map(|value| boundary_from_json(name.clone(), &value))
+        .unwrap_or_else(|| AreaBoundary { name, rings: Vec::new() });
+    if progress.is_cancelled() {
+        return;
+    }
+    let bounds = boundary.bounds();
+    let layers = overpass_settings.get_layers_to_fetch();
+    let _ = sender.send(OverpassMessage::Area { id: progress.id(), boundary, layers });
+    let Some(bounds) = bounds else {
+        return finish_request(progress, false, sender);
+    };
+
+    let mut query = String::default();
+    for (category, key) in overpass_settings.get_true_keys_with_category() {
+        if key == "n/a" {
+            continue;
+        } else if key == "*" {
+            query.push_str(&format!(r#"way["{}"](area.a);"#, category.to_lowercase()));
+        } else {
+            query.push_str(&format!(r#"way["{}"="{}"](area.a);"#, category.to_lowercase(), key.to_lowercase()));
+        }
+    }
     if query.is_empty() {
-        return vec![];
+        return finish_request(progress, true, sender);
     }
-    let url = "https://overpass-api.de/api/interpreter";
-    // let url = "http://localhost:12345/api/interpreter";
-    info!("Sending query: {}", query);
-    let mut status = 429;
-    while status == 429 {
-        if let Ok(response) = ureq::post(url).send_string(&query) {
-            if response.status() == 200 {
-                status = 200;
-                let reader: BufReader<Box<dyn Read + Send + Sync>> = BufReader::new(response.into_reader());
-            
-                let mut response_body = String::default();
-                info!("Finished query...");
-                // Accumulate chunks into a single string
-                for line in reader.lines() {
-                    match line {
-                        Ok(part) => response_body.push_str(part.as_str()),
-                        Err(e) => {
-                            info!("Error reading response: {}", e);
-                            return vec![];
+    let query = format!("[out:json];{}({});(._;>;);\nout body geom;", area_statement, query);
+    let meta = send_overpass_query(query, progress, sender, OverpassMessage::Features);
+    if let Some(meta) = &meta {
+        let _ = sender.send(OverpassMessage::Source(DataSource {
+            bounds,
+            source: OVERPASS_URL.to_string(),
+            generator: meta.generator.clone(),
+            timestamp: meta.timestamp_osm_base.clone(),
+            copyright: meta.copyright.clone(),
+            fetched_at: std::time::SystemTime::now(),
+        }));
+    }
+    finish_request(progress, meta.is_some(), sender);
+}
+
+/// Pulls the outer ways out of a boundary relation, or the way itself if the area was made from one.
+fn boundary_from_json(name: String, value: &serde_json::Value) -> AreaBoundary {
+    let to_coords = |geometry: &serde_json::Value| -> Vec<LonLat> {
+        geometry.as_array().map(|points| points.iter().filter_map(|p| {
+            Some(LonLat::new(p.get("lon")?.as_f64()?, p.get("lat")?.as_f64()?))
+        }).collect()).unwrap_or_default()
+    };
+
+    let mut segments = Vec::new();
+    for element in value.get("elements").and_then(|e| e.as_array()).into_iter().flatten() {
+        match element.get("type").and_then(|t| t.as_str()) {
+            Some("relation") => {
+                for member in element.get("members").and_then(|m| m.as_array()).into_iter().flatten() {
+                    let role = member.get("role").and_then(|r| r.as_str()).unwrap_or_default();
+                    if role == "outer" || role.is_empty() {
+                        if let Some(geometry) = member.get("geometry") {
+                            segments.push(to_coords(geometry));
                         }
                     }
                 }
-    
-                let map_features = map_bundle.features.clone();
-                let features = get_data_from_string_osm(&response_body);
-                info!("Status of rspns: {}", status);
-                if features.is_ok() {
-                    let new_features: Vec<_> = features.unwrap()
-                    .into_iter()
-                    .filter(|feature| {
-                        !map_features
-                            .iter()
-                            .any(|existing| existing.id.contains(&feature.id))
-                    })
-                    .collect();
-                    return new_features
+            }
+            Some("way") => {
+                if let Some(geometry) = element.get("geometry") {
+                    segments.push(to_coords(geometry));
                 }
-                return vec![]
-            } else if response.status() == 429 {
-                info!("Rate limited, waiting 5 seconds");
-                std::thread::sleep(std::time::Duration::from_secs(5));
-            } else {
-                status = 0;
             }
+            _ => {}
         }
     }
-    vec![]
+
+    AreaBoundary { name, rings: assemble_rings(segments) }
+}
+
+/// Requests the layers of each region as they were at `date`, the features are streamed back as a snapshot.
+pub fn get_overpass_snapshot(regions: Vec<LayerRegion>, date: &str, progress: &RequestProgress, sender: &Sender<OverpassMessage>) {
+    if regions.is_empty() {
+        return finish_request(progress, true, sender);
+    }
+    let query = build_layer_query(&format!("[out:json][date:\"{}\"];(", date), ");(._;>;);\nout body geom;", regions);
+    if query == "ERR" {
+        return finish_request(progress, true, sender);
+    }
+    let meta = send_overpass_query(query, progress, sender, |features| OverpassMessage::Snapshot { date: date.to_string(), features });
+    finish_request(progress, meta.is_some(), sender);
 }
 
+/// Sends the query and streams the response back, `wrap` turns each chunk of features into a message.
+/// Features which are already loaded are dropped when they are inserted, so nothing is filtered here.
+fn send_overpass_query(query: String, progress: &RequestProgress, sender: &Sender<OverpassMessage>, wrap: impl Fn(Vec<MapFeature>) -> OverpassMessage) -> Option<OverpassMeta> {
+    if query.is_empty() {
+        return None;
+    }
+    info!("Sending query: {}", query);
+    let response = post_overpass_query(&query, progress)?;
+    info!("Streaming query...");
+    // The body is parsed as it comes in, so the first chunks get drawn while the rest is still downloading
+    let streamed = stream_data_from_reader_osm(progress.reader(response.into_reader()), FEATURE_CHUNK_SIZE, |chunk| {
+        // If the receiver has gone or the request was cancelled there is no point carrying on
+        !progress.is_cancelled() && sender.send(wrap(chunk)).is_ok()
+    });
+    match streamed {
+        Ok(meta) => {
+            info!("Finished query...");
+            Some(meta)
+        }
+        Err(e) => {
+            info!("Error reading response: {}", e);
+            None
+        }
+    }
+}
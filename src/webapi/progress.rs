@@ -0,0 +1,189 @@
+use std::{
+    io::{self, Read},
+    sync::{atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering}, Arc},
+    time::{Duration, Instant},
+};
+
+use crate::map::LonLatRect;
+
+pub type RequestId = u64;
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Where a request has got to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RequestPhase {
+    Queued,         // Not sent yet, or waiting to be sent again after being rate limited
+    Waiting,        // Sent and waiting on the server to answer
+    Downloading,    // The body is coming in, it is parsed as it arrives
+    Parsing,        // Everything has arrived and the last of it is being parsed
+    Spawning,       // The features are all with the map and are being drawn
+    Failed,
+}
+
+impl RequestPhase {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => RequestPhase::Queued,
+            1 => RequestPhase::Waiting,
+            2 => RequestPhase::Downloading,
+            3 => RequestPhase::Parsing,
+            4 => RequestPhase::Spawning,
+            _ => RequestPhase::Failed,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequestPhase::Queued => "Queued",
+            RequestPhase::Waiting => "Waiting on server",
+            RequestPhase::Downloading => "Downloading",
+            RequestPhase::Parsing => "Parsing",
+            RequestPhase::Spawning => "Spawning",
+            RequestPhase::Failed => "Failed",
+        }
+    }
+}
+
+/// How far along a request is, shared between the thread doing the request and the activity widget.
+/// Cloning it gives another handle on the same request.
+#[derive(Clone, Debug)]
+pub struct RequestProgress(Arc<ProgressState>);
+
+#[derive(Debug)]
+struct ProgressState {
+    id: RequestId,
+    kind: String,
+    area: String,
+    started: Instant,
+    bytes: AtomicU64,
+    phase: AtomicU8,
+    cancelled: AtomicBool,
+}
+
+impl RequestProgress {
+    /// `kind` is what sort of request it is and `area` is a description of where it is for.
+    pub fn new(kind: &str, area: String) -> Self {
+        RequestProgress(Arc::new(ProgressState {
+            id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            kind: kind.to_string(),
+            area,
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+            phase: AtomicU8::new(RequestPhase::Queued as u8),
+            cancelled: AtomicBool::new(false),
+        }))
+    }
+
+    /// Describes bounds as lat and long, the same way the data sources window does.
+    pub fn describe_bounds(bounds: &LonLatRect) -> String {
+        format!("{:.4}, {:.4} to {:.4}, {:.4}", bounds.min.lat, bounds.min.lon, bounds.max.lat, bounds.max.lon)
+    }
+
+    pub fn id(&self) -> RequestId {
+        self.0.id
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.0.kind
+    }
+
+    pub fn area(&self) -> &str {
+        &self.0.area
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.0.started.elapsed()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.0.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn phase(&self) -> RequestPhase {
+        RequestPhase::from_u8(self.0.phase.load(Ordering::Relaxed))
+    }
+
+    pub fn set_phase(&self, phase: RequestPhase) {
+        self.0.phase.store(phase as u8, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Sleeps for a while, waking up every so often to see if it has been cancelled.
+    /// Gives back false if it was cancelled before the time was up.
+    pub fn wait(&self, duration: Duration) -> bool {
+        const STEP: Duration = Duration::from_millis(100);
+        let until = Instant::now() + duration;
+        loop {
+            if self.is_cancelled() {
+                return false;
+            }
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+            std::thread::sleep(left.min(STEP));
+        }
+    }
+
+    /// Wraps the body of a response so the bytes are counted, and reading stops with an error once it is cancelled.
+    pub fn reader<R: Read>(&self, inner: R) -> ProgressReader<R> {
+        ProgressReader { inner, progress: self.clone() }
+    }
+}
+
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: RequestProgress,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.progress.is_cancelled() {
+            return Err(io::Error::other("request was cancelled"));
+        }
+        let read = self.inner.read(buf)?;
+        if read == 0 {
+            self.progress.set_phase(RequestPhase::Parsing);
+        } else {
+            self.progress.0.bytes.fetch_add(read as u64, Ordering::Relaxed);
+            self.progress.set_phase(RequestPhase::Downloading);
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_the_whole_time() {
+        let progress = RequestProgress::new("test", String::new());
+        let started = Instant::now();
+        assert!(progress.wait(Duration::from_millis(250)));
+        assert!(started.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn waiting_stops_when_cancelled() {
+        let progress = RequestProgress::new("test", String::new());
+        let cancel = progress.clone();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+        });
+        let started = Instant::now();
+        assert!(!progress.wait(Duration::from_secs(30)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        canceller.join().unwrap();
+    }
+}